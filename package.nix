@@ -6,13 +6,10 @@
   makeBinaryWrapper,
   darwin,
   nvd,
-  use-nom ? true,
-  nix-output-monitor ? null,
   rev ? "dirty",
 }:
-assert use-nom -> nix-output-monitor != null;
 let
-  runtimeDeps = [ nvd ] ++ lib.optionals use-nom [ nix-output-monitor ];
+  runtimeDeps = [ nvd ];
   cargoToml = builtins.fromTOML (builtins.readFile ./Cargo.toml);
 in
 rustPlatform.buildRustPackage {
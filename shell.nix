@@ -13,7 +13,6 @@ mkShell {
     (rustfmt.override { asNightly = true; })
     clippy
     nvd
-    nix-output-monitor
     taplo
     yaml-language-server
   ];
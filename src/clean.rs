@@ -87,6 +87,11 @@ impl interface::CleanMode {
             }
         };
 
+        // Fail before removing anything if the garbage collection can't run afterwards
+        if !args.nogc {
+            nix_info::supported()?;
+        }
+
         // Use mutation to raise errors as they come
         let mut profiles_tagged = ProfilesTagged::new();
         for p in profiles {
@@ -227,17 +232,20 @@ impl interface::CleanMode {
             }
         }
 
-        Command::new("nix")
-            .args(["store", "gc"])
-            .dry(args.dry)
-            .message("Performing garbage collection on the nix store")
-            .run()?;
+        if !args.nogc {
+            Command::new("nix")
+                .args(["store", "gc"])
+                .dry(args.dry)
+                .message("Performing garbage collection on the nix store")
+                .run()?;
+        }
 
         Ok(())
     }
 }
 
 #[instrument(ret, level = "debug")]
+#[allow(clippy::regex_creation_in_loops)]
 fn profiles_in_dir<P: AsRef<Path> + fmt::Debug>(dir: P) -> Vec<PathBuf> {
     let mut res = Vec::new();
     let dir = dir.as_ref();
@@ -1,14 +1,18 @@
 use std::ffi::{OsStr, OsString};
+use std::io::{BufRead, BufReader};
+use std::time::Instant;
 
 use color_eyre::{
-    eyre::{bail, Context},
+    eyre::{bail, eyre, Context},
     Result,
 };
 use subprocess::{Exec, ExitStatus, Redirection};
 use thiserror::Error;
 use tracing::{debug, info};
 
+use crate::history::{self, BuildRecord};
 use crate::installable::Installable;
+use crate::progress::{store_path_name, Progress};
 
 #[derive(Debug)]
 pub struct Command {
@@ -61,8 +65,9 @@ impl Command {
         self
     }
 
-    pub fn run(&self) -> Result<()> {
-        let cmd = if self.elevate {
+    /// The command to execute, behind sudo when elevating
+    fn exec(&self) -> Result<Exec> {
+        Ok(if self.elevate {
             let cmd = if cfg!(target_os = "macos") {
                 // Check for if sudo has the preserve-env flag
                 Exec::cmd("sudo").args(
@@ -86,9 +91,14 @@ impl Command {
             cmd.arg(&self.command).args(&self.args)
         } else {
             Exec::cmd(&self.command).args(&self.args)
-        }
-        .stderr(Redirection::None)
-        .stdout(Redirection::None);
+        })
+    }
+
+    pub fn run(&self) -> Result<()> {
+        let cmd = self
+            .exec()?
+            .stderr(Redirection::None)
+            .stdout(Redirection::None);
 
         if let Some(m) = &self.message {
             info!("{}", m);
@@ -107,9 +117,10 @@ impl Command {
         Ok(())
     }
 
+    /// Run the command and return its stdout, behind sudo when elevating like [`Self::run`]
     pub fn run_capture(&self) -> Result<Option<String>> {
-        let cmd = Exec::cmd(&self.command)
-            .args(&self.args)
+        let cmd = self
+            .exec()?
             .stderr(Redirection::None)
             .stdout(Redirection::Pipe);
 
@@ -132,7 +143,8 @@ pub struct Build {
     message: Option<String>,
     installable: Installable,
     extra_args: Vec<OsString>,
-    nom: bool,
+    progress: bool,
+    history: Option<String>,
 }
 
 impl Build {
@@ -141,7 +153,8 @@ impl Build {
             message: None,
             installable,
             extra_args: vec![],
-            nom: false,
+            progress: false,
+            history: None,
         }
     }
 
@@ -155,8 +168,16 @@ impl Build {
         self
     }
 
-    pub fn nom(mut self, yes: bool) -> Self {
-        self.nom = yes;
+    /// Render build progress from nix's internal-json logs instead of its raw output
+    pub fn progress(mut self, yes: bool) -> Self {
+        self.progress = yes;
+        self
+    }
+
+    /// Record the build under this hostname, with the timings of each derivation when
+    /// progress is rendered
+    pub fn history<S: AsRef<str>>(mut self, hostname: S) -> Self {
+        self.history = Some(hostname.as_ref().to_owned());
         self
     }
 
@@ -176,22 +197,38 @@ impl Build {
             info!("{}", m);
         }
 
-        let installable_args = self.installable.to_args();
+        let installable_args = self.installable.to_args()?;
 
-        let exit = if self.nom {
-            let cmd = {
-                Exec::cmd("nix")
-                    .arg("build")
-                    .args(&installable_args)
-                    .args(&["--log-format", "internal-json", "--verbose"])
-                    .args(&self.extra_args)
-                    .stdout(Redirection::Pipe)
-                    .stderr(Redirection::Merge)
-                    | Exec::cmd("nom").args(&["--json"])
-            }
-            .stdout(Redirection::None);
+        let exit = if self.progress {
+            let cmd = Exec::cmd("nix")
+                .arg("build")
+                .args(&installable_args)
+                .args(&["--log-format", "internal-json", "--verbose"])
+                .args(&self.extra_args)
+                .stdout(Redirection::None)
+                .stderr(Redirection::Pipe);
             debug!(?cmd);
-            cmd.join()
+
+            let mut process = cmd.popen()?;
+            let stderr = process.stderr.take().expect("stderr is piped");
+
+            let mut progress = Progress::new();
+            for line in BufReader::new(stderr).lines() {
+                progress.feed(&line?);
+            }
+
+            let exit = process.wait()?;
+            progress.finish(exit.success());
+
+            if let Some(hostname) = &self.history {
+                history::record(&BuildRecord::new(
+                    hostname,
+                    &progress.state,
+                    exit.success(),
+                    progress.elapsed(),
+                ));
+            }
+            exit
         } else {
             let cmd = Exec::cmd("nix")
                 .arg("build")
@@ -201,16 +238,192 @@ impl Build {
                 .stderr(Redirection::Merge);
 
             debug!(?cmd);
-            cmd.join()
+            let started = Instant::now();
+            let exit = cmd.join()?;
+
+            if let Some(hostname) = &self.history {
+                history::record(&BuildRecord::unlogged(
+                    hostname,
+                    exit.success(),
+                    started.elapsed(),
+                ));
+            }
+            exit
         };
 
-        match exit? {
-            ExitStatus::Exited(0) => (),
-            other => bail!(ExitError(other)),
+        match exit {
+            ExitStatus::Exited(0) => Ok(()),
+            other => {
+                let err = eyre!(ExitError(other));
+                match &self.message {
+                    Some(m) => Err(err.wrap_err(m.clone())),
+                    None => Err(err),
+                }
+            }
         }
+    }
 
-        Ok(())
+    /// Ask nix what building the installable would realise, without building anything
+    pub fn dry_run(&self) -> Result<DryRun> {
+        if let Some(m) = &self.message {
+            info!("{}", m);
+        }
+
+        let cmd = Exec::cmd("nix")
+            .args(&["build", "--dry-run"])
+            .args(&self.installable.to_args()?)
+            .args(&self.extra_args)
+            .stdout(Redirection::Pipe)
+            .stderr(Redirection::Pipe);
+
+        debug!(?cmd);
+        let capture = cmd.capture()?;
+        let stderr = capture.stderr_str();
+
+        if !capture.success() {
+            eprint!("{}", stderr);
+            bail!(ExitError(capture.exit_status));
+        }
+
+        let dry_run = DryRun::parse(&stderr);
+        for line in &dry_run.messages {
+            eprintln!("{}", line);
+        }
+
+        Ok(dry_run)
+    }
+}
+
+/// What `nix build --dry-run` reports it would do
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct DryRun {
+    /// Derivations that would be built locally
+    pub to_build: Vec<String>,
+
+    /// Store paths that would be fetched from a substituter
+    pub to_fetch: Vec<String>,
+
+    /// Total download size, as reported by nix (e.g. "12.34 MiB")
+    pub download_size: Option<String>,
+
+    /// Total unpacked size, as reported by nix
+    pub unpacked_size: Option<String>,
+
+    /// Any other output from nix, like evaluation warnings
+    pub messages: Vec<String>,
+}
+
+impl DryRun {
+    pub fn parse(output: &str) -> Self {
+        enum Section {
+            None,
+            Build,
+            Fetch,
+        }
+
+        let sizes = regex::Regex::new(r"\(([^,]+) download, ([^)]+) unpacked\)").unwrap();
+
+        let mut res = Self::default();
+        let mut section = Section::None;
+
+        for line in output.lines() {
+            if let Some(path) = line.strip_prefix("  ") {
+                match section {
+                    Section::Build => {
+                        res.to_build.push(path.trim().to_owned());
+                        continue;
+                    }
+                    Section::Fetch => {
+                        res.to_fetch.push(path.trim().to_owned());
+                        continue;
+                    }
+                    Section::None => {}
+                }
+            }
+
+            if line.ends_with("will be built:") {
+                section = Section::Build;
+            } else if line.contains("will be fetched") {
+                section = Section::Fetch;
+                if let Some(caps) = sizes.captures(line) {
+                    res.download_size = Some(caps[1].to_owned());
+                    res.unpacked_size = Some(caps[2].to_owned());
+                }
+            } else {
+                section = Section::None;
+                if !line.trim().is_empty() {
+                    res.messages.push(line.to_owned());
+                }
+            }
+        }
+
+        res
     }
+
+    pub fn print(&self) {
+        use owo_colors::OwoColorize;
+
+        if self.to_build.is_empty() && self.to_fetch.is_empty() {
+            info!("Nothing to build or fetch, everything is already in the store");
+            return;
+        }
+
+        println!();
+        if !self.to_build.is_empty() {
+            println!(
+                "{}",
+                format!("Derivations to build ({})", self.to_build.len())
+                    .blue()
+                    .bold()
+            );
+            for drv in &self.to_build {
+                println!("- {}", store_path_name(drv));
+            }
+            println!();
+        }
+
+        if !self.to_fetch.is_empty() {
+            let mut header = format!("Paths to fetch ({}", self.to_fetch.len());
+            if let (Some(download), Some(unpacked)) = (&self.download_size, &self.unpacked_size) {
+                header.push_str(&format!(", {download} download, {unpacked} unpacked"));
+            }
+            header.push(')');
+
+            println!("{}", header.blue().bold());
+            for path in &self.to_fetch {
+                println!("- {}", store_path_name(path));
+            }
+            println!();
+        }
+    }
+}
+
+#[test]
+fn test_dry_run_parse() {
+    let output = r#"warning: Git tree '/home/user/flake' is dirty
+these 2 derivations will be built:
+  /nix/store/0r3s7g9a9x0p3vlcjq3w6n5d9a1m4b2c-etc.drv
+  /nix/store/1r3s7g9a9x0p3vlcjq3w6n5d9a1m4b2c-nixos-system-host.drv
+these 3 paths will be fetched (12.34 MiB download, 56.78 MiB unpacked):
+  /nix/store/2r3s7g9a9x0p3vlcjq3w6n5d9a1m4b2c-hello-2.12.1
+  /nix/store/3r3s7g9a9x0p3vlcjq3w6n5d9a1m4b2c-ripgrep-14.1.0
+  /nix/store/4r3s7g9a9x0p3vlcjq3w6n5d9a1m4b2c-fd-10.1.0
+"#;
+
+    let res = DryRun::parse(output);
+    assert_eq!(res.to_build.len(), 2);
+    assert_eq!(res.to_fetch.len(), 3);
+    assert_eq!(res.download_size.as_deref(), Some("12.34 MiB"));
+    assert_eq!(res.unpacked_size.as_deref(), Some("56.78 MiB"));
+    assert_eq!(
+        res.messages,
+        vec!["warning: Git tree '/home/user/flake' is dirty"]
+    );
+    assert_eq!(store_path_name(&res.to_fetch[0]), "hello-2.12.1");
+
+    let res = DryRun::parse("this derivation will be built:\n  /nix/store/foo.drv\n");
+    assert_eq!(res.to_build, vec!["/nix/store/foo.drv"]);
+    assert!(res.to_fetch.is_empty());
 }
 
 #[derive(Debug, Error)]
@@ -0,0 +1,499 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use clap::builder::ArgPredicate;
+use clap::ValueEnum;
+use color_eyre::eyre::Context;
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::flake_ref;
+use crate::installable::Installable;
+use crate::interface::{CommitLock, ConfigArgs, ConfigSubcommand};
+use crate::util;
+use crate::Result;
+
+const SYSTEM_CONFIG: &str = "/etc/nh/nh.toml";
+const CONFIG_FILE: &str = "nh.toml";
+
+static CONFIG: OnceCell<LoadedConfig> = OnceCell::new();
+
+/// Settings read from `nh.toml`
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub rebuild: RebuildConfig,
+    pub clean: CleanConfig,
+    pub search: SearchConfig,
+    pub hooks: HooksConfig,
+}
+
+/// Defaults for `nh os`, `nh home` and `nh darwin` rebuilds
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RebuildConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ask: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub no_nom: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub out_link: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub notify: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub require_clean: Option<bool>,
+    /// When to commit flake.lock after an update, `build` or `switch`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub commit_lock: Option<CommitLock>,
+    /// Globs of the flake inputs to warn about when they get old, defaults to nixpkgs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stale_inputs: Option<Vec<String>>,
+    /// Age after which an input is considered old, defaults to 30 days
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stale_after: Option<String>,
+    /// Passed to nix build before the ones given on the command line
+    pub extra_args: Vec<String>,
+}
+
+impl RebuildConfig {
+    pub fn stale_inputs(&self) -> Vec<String> {
+        self.stale_inputs
+            .clone()
+            .unwrap_or_else(|| vec![String::from("nixpkgs")])
+    }
+
+    pub fn stale_after(&self) -> Result<Duration> {
+        match &self.stale_after {
+            Some(age) => Ok(humantime::parse_duration(age)
+                .wrap_err(format!("Invalid rebuild.stale_after `{age}`"))?),
+            None => Ok(Duration::from_secs(30 * 24 * 60 * 60)),
+        }
+    }
+}
+
+/// Defaults for `nh clean`
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CleanConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keep: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keep_since: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ask: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nogc: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nogcroots: Option<bool>,
+}
+
+/// Defaults for `nh search`
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SearchConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channel: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HooksConfig {
+    pub os: Hooks,
+    pub home: Hooks,
+    pub darwin: Hooks,
+}
+
+/// Shell commands to run around each phase of a rebuild
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Hooks {
+    pub pre_build: Vec<String>,
+    pub post_build: Vec<String>,
+    pub pre_activate: Vec<String>,
+    pub post_activate: Vec<String>,
+    pub on_failure: Vec<String>,
+}
+
+#[derive(Debug)]
+struct LoadedConfig {
+    config: Config,
+    files: Vec<PathBuf>,
+    hostname: Option<String>,
+    /// Whether the defaults are used because the configuration couldn't be loaded
+    invalid: bool,
+}
+
+/// Configuration files, from lowest to highest priority. The last one is the nh.toml of the
+/// flake the command works on, or of the one in `NH_FLAKE` for commands without installable.
+fn config_files(installable: Option<&Installable>) -> Vec<PathBuf> {
+    let mut res = vec![PathBuf::from(SYSTEM_CONFIG)];
+
+    match util::config_dir() {
+        Ok(dir) => res.push(dir.join(CONFIG_FILE)),
+        Err(err) => debug!(?err, "Not loading user configuration"),
+    }
+
+    let flake_dir = match installable {
+        Some(Installable::Flake { reference, .. }) => flake_ref::local_dir(reference),
+        Some(_) => None,
+        None => local_flake_dir(),
+    };
+    if let Some(dir) = flake_dir {
+        res.push(dir.join(CONFIG_FILE));
+    }
+
+    res
+}
+
+#[test]
+fn test_config_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let dir = fs::canonicalize(dir.path()).unwrap();
+    fs::write(dir.join("flake.nix"), "{ }").unwrap();
+
+    let flake = Installable::Flake {
+        reference: dir.to_string_lossy().into_owned(),
+        attribute: vec![],
+        outputs: crate::installable::OutputsSpec::Default,
+    };
+    assert_eq!(
+        config_files(Some(&flake)).last(),
+        Some(&dir.join(CONFIG_FILE))
+    );
+
+    let file = Installable::File {
+        path: dir.join("default.nix"),
+        attribute: vec![],
+        outputs: crate::installable::OutputsSpec::Default,
+    };
+    assert!(!config_files(Some(&file)).contains(&dir.join(CONFIG_FILE)));
+}
+
+/// Directory of the flake in `NH_FLAKE`, if it is a local one
+fn local_flake_dir() -> Option<PathBuf> {
+    let flake = std::env::var("NH_FLAKE").ok()?;
+    flake_ref::local_dir(flake.split('#').next()?)
+}
+
+fn loaded(installable: Option<&Installable>) -> Result<&'static LoadedConfig> {
+    CONFIG.get_or_try_init(|| {
+        let files = config_files(installable);
+        let hostname = hostname::get()
+            .ok()
+            .map(|h| h.to_string_lossy().into_owned());
+        let (config, files) = load(&files, hostname.as_deref())?;
+        Ok(LoadedConfig {
+            config,
+            files,
+            hostname,
+            invalid: false,
+        })
+    })
+}
+
+/// The configuration loaded by [`init`]
+pub fn get() -> Result<&'static Config> {
+    Ok(&loaded(None)?.config)
+}
+
+/// Load and merge all the configuration files, including the one of the flake of
+/// `installable`. With `fallback`, only warn if it is broken and use the defaults instead,
+/// for the commands that help fixing it.
+pub fn init(fallback: bool, installable: Option<&Installable>) -> Result<&'static Config> {
+    match loaded(installable) {
+        Ok(loaded) => Ok(&loaded.config),
+        Err(err) if fallback => {
+            warn!("Ignoring the nh configuration: {err:#}");
+            let loaded = CONFIG.get_or_init(|| LoadedConfig {
+                config: Config::default(),
+                files: Vec::new(),
+                hostname: None,
+                invalid: true,
+            });
+            Ok(&loaded.config)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Merge the files that exist, then the `[hosts.<hostname>]` overrides on top
+fn load(files: &[PathBuf], hostname: Option<&str>) -> Result<(Config, Vec<PathBuf>)> {
+    let mut merged = toml::Table::new();
+    let mut found = Vec::new();
+
+    for file in files {
+        if let Some(table) = read_table(file)? {
+            debug!(?file, "Loading configuration");
+            merge(&mut merged, table);
+            found.push(file.clone());
+        }
+    }
+
+    let config = from_table(merged, hostname).wrap_err("Invalid nh configuration")?;
+    Ok((config, found))
+}
+
+fn from_table(mut table: toml::Table, hostname: Option<&str>) -> Result<Config> {
+    if let Some(hosts) = table.remove("hosts") {
+        let toml::Value::Table(mut hosts) = hosts else {
+            color_eyre::eyre::bail!("`hosts` must be a table of hostnames");
+        };
+
+        if let Some(toml::Value::Table(host)) = hostname.and_then(|h| hosts.remove(h)) {
+            debug!(?hostname, "Applying host configuration");
+            merge(&mut table, host);
+        }
+    }
+
+    Ok(Config::deserialize(table)?)
+}
+
+fn read_table(file: &Path) -> Result<Option<toml::Table>> {
+    let content = match fs::read_to_string(file) {
+        Ok(c) => c,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err).wrap_err(format!("Reading {}", file.display())),
+    };
+
+    let table = content
+        .parse::<toml::Table>()
+        .wrap_err(format!("Parsing {}", file.display()))?;
+
+    Ok(Some(table))
+}
+
+/// Recursively merge `overlay` into `base`, with the values of `overlay` taking precedence
+fn merge(base: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(overlay)) => merge(base, overlay),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_merge() {
+    let mut base: toml::Table = toml::toml! {
+        [hooks.os]
+        pre_build = ["echo system"]
+        post_build = ["echo done"]
+    };
+    let overlay: toml::Table = toml::toml! {
+        [hooks.os]
+        pre_build = ["echo user"]
+    };
+
+    merge(&mut base, overlay);
+    let config = Config::deserialize(base).unwrap();
+
+    assert_eq!(config.hooks.os.pre_build, vec!["echo user"]);
+    assert_eq!(config.hooks.os.post_build, vec!["echo done"]);
+}
+
+#[test]
+fn test_host_overrides() {
+    let table: toml::Table = toml::toml! {
+        [clean]
+        keep = 3
+        keep_since = "7d"
+
+        [hosts.laptop.clean]
+        keep = 1
+    };
+
+    let config = from_table(table.clone(), Some("laptop")).unwrap();
+    assert_eq!(config.clean.keep, Some(1));
+    assert_eq!(config.clean.keep_since.as_deref(), Some("7d"));
+
+    let config = from_table(table, Some("desktop")).unwrap();
+    assert_eq!(config.clean.keep, Some(3));
+}
+
+impl Config {
+    /// Command line defaults, as (top-level subcommands, argument id, value)
+    fn arg_defaults(&self) -> Vec<(&'static [&'static str], &'static str, String)> {
+        const REBUILD: &[&str] = &["os", "home", "darwin"];
+        const CLEAN: &[&str] = &["clean"];
+        const SEARCH: &[&str] = &["search"];
+
+        let mut res = Vec::new();
+        let mut push = |commands, id, value: Option<String>| {
+            if let Some(value) = value {
+                res.push((commands, id, value));
+            }
+        };
+
+        let r = &self.rebuild;
+        push(REBUILD, "ask", r.ask.map(|v| v.to_string()));
+        push(REBUILD, "no_nom", r.no_nom.map(|v| v.to_string()));
+        push(REBUILD, "notify", r.notify.map(|v| v.to_string()));
+        push(
+            REBUILD,
+            "require_clean",
+            r.require_clean.map(|v| v.to_string()),
+        );
+        push(
+            REBUILD,
+            "commit_lock",
+            r.commit_lock
+                .and_then(|v| v.to_possible_value())
+                .map(|v| v.get_name().to_owned()),
+        );
+        push(
+            REBUILD,
+            "out_link",
+            r.out_link
+                .as_ref()
+                .map(|p| p.to_string_lossy().into_owned()),
+        );
+
+        let c = &self.clean;
+        push(CLEAN, "keep", c.keep.map(|v| v.to_string()));
+        push(CLEAN, "keep_since", c.keep_since.clone());
+        push(CLEAN, "ask", c.ask.map(|v| v.to_string()));
+        push(CLEAN, "nogc", c.nogc.map(|v| v.to_string()));
+        push(CLEAN, "nogcroots", c.nogcroots.map(|v| v.to_string()));
+
+        let s = &self.search;
+        push(SEARCH, "limit", s.limit.map(|v| v.to_string()));
+        push(SEARCH, "channel", s.channel.clone());
+
+        res
+    }
+
+    /// Use the configured values as the defaults of the command line, so that flags still win
+    pub fn apply_defaults(&self, mut cmd: clap::Command) -> clap::Command {
+        for (commands, id, value) in self.arg_defaults() {
+            for name in commands {
+                cmd = cmd.mut_subcommand(name, |sub| set_default(sub, id, &value));
+            }
+        }
+        cmd
+    }
+}
+
+/// The flag that turns off a boolean flag from the command line
+fn negation(id: &str) -> String {
+    match id {
+        "no_nom" => String::from("nom"),
+        "nogc" => String::from("gc"),
+        "nogcroots" => String::from("gcroots"),
+        id => format!("no_{id}"),
+    }
+}
+
+fn set_default(mut cmd: clap::Command, id: &str, value: &str) -> clap::Command {
+    let flag = cmd
+        .get_arguments()
+        .find(|a| a.get_id() == id)
+        .map(|a| matches!(a.get_action(), clap::ArgAction::SetTrue));
+    if let Some(flag) = flag {
+        let negation = negation(id);
+        let negatable = flag && cmd.get_arguments().any(|a| a.get_id() == negation.as_str());
+        let value = value.to_owned();
+        cmd = cmd.mut_arg(id, |arg| {
+            let arg = arg.default_value(value);
+            // A flag enabled in the configuration is only turned off by its negation
+            if negatable {
+                arg.default_value_if(negation, ArgPredicate::Equals("true".into()), "false")
+            } else {
+                arg
+            }
+        });
+    }
+
+    let subcommands: Vec<String> = cmd
+        .get_subcommands()
+        .map(|s| s.get_name().to_owned())
+        .collect();
+    for name in subcommands {
+        cmd = cmd.mut_subcommand(name, |sub| set_default(sub, id, value));
+    }
+
+    cmd
+}
+
+#[test]
+fn test_apply_defaults() {
+    use clap::{CommandFactory, FromArgMatches};
+
+    use crate::interface::{Main, NHCommand};
+
+    let config = Config {
+        rebuild: RebuildConfig {
+            ask: Some(true),
+            ..Default::default()
+        },
+        clean: CleanConfig {
+            nogc: Some(true),
+            ..Default::default()
+        },
+        search: SearchConfig {
+            limit: Some(5),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    let cmd = config.apply_defaults(Main::command());
+    let parse = |args: &[&str]| {
+        let matches = cmd.clone().try_get_matches_from(args).unwrap();
+        match Main::from_arg_matches(&matches).unwrap().command {
+            NHCommand::Search(args) => args.limit,
+            _ => unreachable!(),
+        }
+    };
+
+    assert_eq!(parse(&["nh", "search", "hello"]), 5);
+    assert_eq!(parse(&["nh", "search", "--limit", "2", "hello"]), 2);
+
+    // Flags of a sub-subcommand, like `nh os switch`
+    let flag = |args: &[&str], id: &str| {
+        let matches = cmd.clone().try_get_matches_from(args).unwrap();
+        let (_, command) = matches.subcommand().unwrap();
+        let (_, subcommand) = command.subcommand().unwrap();
+        subcommand.get_flag(id)
+    };
+    let ask = |args: &[&str]| flag(args, "ask");
+    assert!(ask(&["nh", "os", "switch"]));
+    assert!(!ask(&["nh", "os", "switch", "--no-ask"]));
+    assert!(ask(&["nh", "os", "switch", "--no-ask", "--ask"]));
+    assert!(!ask(&["nh", "os", "switch", "--ask", "--no-ask"]));
+
+    let nogc = |args: &[&str]| flag(args, "nogc");
+    assert!(nogc(&["nh", "clean", "all"]));
+    assert!(!nogc(&["nh", "clean", "all", "--gc"]));
+}
+
+impl ConfigArgs {
+    pub fn run(&self) -> Result<()> {
+        match self.subcommand {
+            ConfigSubcommand::Show => {
+                let loaded = loaded(None)?;
+
+                if loaded.invalid {
+                    println!("# The configuration is invalid, showing the defaults");
+                } else if loaded.files.is_empty() {
+                    println!("# No configuration files found, showing the defaults");
+                }
+                for file in &loaded.files {
+                    println!("# Loaded {}", file.display());
+                }
+                if let Some(hostname) = &loaded.hostname {
+                    println!("# Host overrides are taken from [hosts.{hostname}]");
+                }
+                println!();
+                print!("{}", toml::to_string_pretty(&loaded.config)?);
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -3,10 +3,19 @@ use tracing::{debug, info, warn};
 
 use crate::commands;
 use crate::commands::Command;
+use crate::config;
+use crate::flake_lock;
+use crate::flake_ref;
+use crate::git;
+use crate::hooks::{HookRunner, Phase, Rejected};
 use crate::installable::Installable;
-use crate::interface::{DarwinArgs, DarwinRebuildArgs, DarwinReplArgs, DarwinSubcommand};
+use crate::interface::{
+    CommitLock, DarwinArgs, DarwinRebuildArgs, DarwinReplArgs, DarwinSubcommand,
+};
+use crate::nix_info;
 use crate::nixos::toplevel_for;
-use crate::update::{pull, update};
+use crate::notify::Notifier;
+use crate::update::{pull, update, LockUpdate};
 use crate::Result;
 
 const SYSTEM_PROFILE: &str = "/nix/var/nix/profiles/system";
@@ -18,8 +27,8 @@ impl DarwinArgs {
         match self.subcommand {
             DarwinSubcommand::Switch(args) => args.rebuild(Switch),
             DarwinSubcommand::Build(args) => {
-                if args.common.ask || args.common.dry {
-                    warn!("`--ask` and `--dry` have no effect for `nh darwin build`");
+                if args.common.ask {
+                    warn!("`--ask` has no effect for `nh darwin build`");
                 }
                 args.rebuild(Build)
             }
@@ -28,6 +37,7 @@ impl DarwinArgs {
     }
 }
 
+#[derive(Debug)]
 enum DarwinRebuildVariant {
     Switch,
     Build,
@@ -65,36 +75,83 @@ fn get_hostname(hostname: Option<String>) -> Result<String> {
 }
 
 impl DarwinRebuildArgs {
-    fn rebuild(self, variant: DarwinRebuildVariant) -> Result<()> {
+    fn rebuild(mut self, variant: DarwinRebuildVariant) -> Result<()> {
+        nix_info::supported()?;
+        let config = config::get()?;
+        self.extra_args
+            .splice(0..0, config.rebuild.extra_args.iter().cloned());
+
+        let variant_name = format!("{variant:?}").to_lowercase();
+        let notifier = Notifier::new(self.common.notify, format!("nh darwin {variant_name}"));
+        let mut hooks = HookRunner::new(&config.hooks.darwin, &variant_name);
+
+        let mut lock = None;
+        let res = self.rebuild_steps(variant, config, &notifier, &mut hooks, &mut lock);
+        if let Some(lock) = &lock {
+            lock.finished(res.is_ok());
+        }
+        if let Err(err) = &res {
+            hooks.failed(err);
+        }
+        notifier.finished(&res);
+        res
+    }
+
+    fn rebuild_steps(
+        mut self,
+        variant: DarwinRebuildVariant,
+        config: &config::Config,
+        notifier: &Notifier,
+        hooks: &mut HookRunner,
+        lock: &mut Option<LockUpdate>,
+    ) -> Result<()> {
         use DarwinRebuildVariant::*;
 
         if nix::unistd::Uid::effective().is_root() {
             bail!("Don't run nh os as root. I will call sudo internally as needed");
         }
 
-        if self.common.pull {
+        self.common.installable = self.common.installable.resolve()?;
+
+        if self.common.pull.pull {
             pull(
                 &self.common.installable,
-                self.update_args.update,
+                &self.common.pull,
+                self.update_args.enabled(),
                 self.common.dry,
             )?;
         }
 
-        if self.update_args.update {
-            update(&self.common.installable, self.update_args.update_input)?;
+        if self.update_args.enabled() {
+            // The channels of the system are root's
+            *lock = update(
+                &self.common.installable,
+                &self.update_args,
+                true,
+                self.common.dry,
+            )?;
         }
 
-        let hostname = get_hostname(self.hostname)?;
+        git::check_clean(
+            &self.common.installable,
+            self.common.require_clean,
+            self.update_args.enabled(),
+        )?;
+        let rebuild_config = &config.rebuild;
+        flake_lock::warn_stale(
+            &self.common.installable,
+            &rebuild_config.stale_inputs(),
+            rebuild_config.stale_after()?,
+        )?;
 
-        let out_path: Box<dyn crate::util::MaybeTempPath> = match self.common.out_link {
-            Some(ref p) => Box::new(p.clone()),
-            None => Box::new({
-                let dir = tempfile::Builder::new().prefix("nh-os").tempdir()?;
-                (dir.as_ref().join("result"), dir)
-            }),
-        };
-
-        debug!(?out_path);
+        let hostname = get_hostname(self.hostname)?;
+        let hostname = flake_ref::select_configuration(
+            &self.common.installable,
+            "darwinConfigurations",
+            &hostname,
+            &self.extra_args,
+        )?;
+        hooks.hostname(&hostname);
 
         let mut installable = self.common.installable.clone();
         if let Installable::Flake {
@@ -110,28 +167,49 @@ impl DarwinRebuildArgs {
 
         let toplevel = toplevel_for(hostname, installable);
 
-        if self.common.pull {
-            if let Installable::Flake { reference, .. } = &self.common.installable {
-                commands::Command::new("git")
-                    .args(["-C", reference, "pull"])
-                    .dry(self.common.dry)
-                    .message("Pulling Flake")
-                    .run()?;
+        if self.common.dry {
+            if self.common.ask {
+                warn!("--ask has no effect as dry run was requested");
             }
+            commands::Build::new(toplevel)
+                .extra_args(&self.extra_args)
+                .message("Evaluating Darwin configuration (dry run)")
+                .dry_run()?
+                .print();
+            return Ok(());
         }
 
+        let out_path: Box<dyn crate::util::MaybeTempPath> = match self.common.out_link {
+            Some(ref p) => Box::new(p.clone()),
+            None => Box::new({
+                let dir = tempfile::Builder::new().prefix("nh-os").tempdir()?;
+                (dir.as_ref().join("result"), dir)
+            }),
+        };
+
+        debug!(?out_path);
+
+        hooks.run(Phase::PreBuild)?;
+
         commands::Build::new(toplevel)
             .extra_arg("--out-link")
             .extra_arg(out_path.get_path())
             .extra_args(&self.extra_args)
             .message("Building Darwin configuration")
-            .nom(!self.common.no_nom)
+            .progress(!self.common.no_nom)
             .run()?;
 
+        if let Some(lock) = lock.as_mut() {
+            lock.reached(CommitLock::Build)?;
+        }
+
         let target_profile = out_path.get_path().to_owned();
 
         target_profile.try_exists().context("Doesn't exist")?;
 
+        hooks.target(&target_profile);
+        hooks.run(Phase::PostBuild)?;
+
         Command::new("nvd")
             .arg("diff")
             .arg(CURRENT_PROFILE)
@@ -139,28 +217,29 @@ impl DarwinRebuildArgs {
             .message("Comparing changes")
             .run()?;
 
-        if self.common.ask && !self.common.dry && !matches!(variant, Build) {
+        if self.common.ask && !matches!(variant, Build) {
             info!("Apply the config?");
+            notifier.confirmation();
             let confirmation = dialoguer::Confirm::new().default(false).interact()?;
 
             if !confirmation {
-                bail!("User rejected the new config");
+                bail!(Rejected);
             }
         }
 
         if let Switch = variant {
+            hooks.run(Phase::PreActivate)?;
+
             Command::new("nix")
                 .args(["build", "--no-link", "--profile", SYSTEM_PROFILE])
                 .arg(out_path.get_path())
                 .elevate(true)
-                .dry(self.common.dry)
                 .run()?;
 
             let switch_to_configuration = out_path.get_path().join("activate-user");
 
             Command::new(switch_to_configuration)
                 .message("Activating configuration for user")
-                .dry(self.common.dry)
                 .run()?;
 
             let switch_to_configuration = out_path.get_path().join("activate");
@@ -168,8 +247,13 @@ impl DarwinRebuildArgs {
             Command::new(switch_to_configuration)
                 .elevate(true)
                 .message("Activating configuration")
-                .dry(self.common.dry)
                 .run()?;
+
+            hooks.run(Phase::PostActivate)?;
+
+            if let Some(lock) = lock.as_mut() {
+                lock.reached(CommitLock::Switch)?;
+            }
         }
 
         // Make sure out_path is not accidentally dropped
@@ -182,13 +266,19 @@ impl DarwinRebuildArgs {
 
 impl DarwinReplArgs {
     fn run(self) -> Result<()> {
-        let mut target_installable = self.installable;
+        let mut target_installable = self.installable.resolve()?;
 
         if matches!(target_installable, Installable::Store { .. }) {
             bail!("Nix doesn't support nix store installables.");
         }
 
         let hostname = get_hostname(self.hostname)?;
+        let hostname = flake_ref::select_configuration(
+            &target_installable,
+            "darwinConfigurations",
+            &hostname,
+            std::iter::empty::<&str>(),
+        )?;
 
         if let Installable::Flake {
             ref mut attribute, ..
@@ -202,7 +292,7 @@ impl DarwinReplArgs {
 
         Command::new("nix")
             .arg("repl")
-            .args(target_installable.to_args())
+            .args(target_installable.to_args()?)
             .run()?;
 
         Ok(())
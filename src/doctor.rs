@@ -0,0 +1,353 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use color_eyre::eyre::bail;
+use nix::errno::Errno;
+use nix::fcntl::AtFlags;
+use nix::unistd::{faccessat, AccessFlags};
+use owo_colors::OwoColorize;
+use serde::Serialize;
+use tracing::debug;
+
+use crate::flake_ref;
+use crate::interface::DoctorArgs;
+use crate::nix_info;
+use crate::nixos::SPEC_LOCATION;
+use crate::util;
+use crate::Result;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Pass,
+    Warn,
+    Fail,
+}
+
+#[derive(Debug, Serialize)]
+struct Check {
+    name: &'static str,
+    status: Status,
+    message: String,
+}
+
+impl Check {
+    fn new<S: Into<String>>(name: &'static str, status: Status, message: S) -> Self {
+        Self {
+            name,
+            status,
+            message: message.into(),
+        }
+    }
+}
+
+impl DoctorArgs {
+    pub fn run(&self) -> Result<()> {
+        let mut checks = Vec::new();
+
+        check_nix(&mut checks);
+        check_binary(
+            &mut checks,
+            "nvd",
+            Status::Fail,
+            "needed to compare generations",
+        );
+        check_binary(&mut checks, "git", Status::Warn, "needed for --pull");
+        check_binary(
+            &mut checks,
+            "sudo",
+            Status::Warn,
+            "needed to activate configurations",
+        );
+        check_env(&mut checks);
+        check_flake(&mut checks);
+        check_profiles(&mut checks);
+        check_gcroots(&mut checks);
+        check_specialisation(&mut checks);
+
+        if self.json {
+            println!("{}", serde_json::to_string_pretty(&checks)?);
+        } else {
+            for check in &checks {
+                let status = match check.status {
+                    Status::Pass => "PASS".green().to_string(),
+                    Status::Warn => "WARN".yellow().to_string(),
+                    Status::Fail => "FAIL".red().to_string(),
+                };
+                println!("[{}] {}: {}", status, check.name.bold(), check.message);
+            }
+        }
+
+        let failed = checks.iter().filter(|c| c.status == Status::Fail).count();
+        if failed > 0 {
+            bail!("{failed} check(s) failed");
+        }
+
+        Ok(())
+    }
+}
+
+/// Run a command and return its trimmed stdout if it succeeded
+fn output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    debug!(program, ?args, ?output);
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+fn check_nix(checks: &mut Vec<Check>) {
+    if util::find_in_path("nix").is_none() {
+        checks.push(Check::new("nix", Status::Fail, "not found in PATH"));
+        return;
+    }
+
+    match nix_info::get() {
+        Ok(info) => match info.ensure_supported() {
+            Ok(()) => checks.push(Check::new("nix", Status::Pass, info.to_string())),
+            Err(err) => checks.push(Check::new("nix", Status::Fail, err.to_string())),
+        },
+        Err(err) => checks.push(Check::new(
+            "nix",
+            Status::Warn,
+            format!("couldn't get the version: {err}"),
+        )),
+    }
+
+    // `nix config show` replaced `nix show-config` in 2.20
+    let features = output("nix", &["config", "show", "experimental-features"])
+        .or_else(|| output("nix", &["show-config"]).and_then(|config| parse_features(&config)));
+
+    match features {
+        None => checks.push(Check::new(
+            "experimental features",
+            Status::Warn,
+            "couldn't read the nix configuration",
+        )),
+        Some(features) => {
+            let missing: Vec<_> = ["nix-command", "flakes"]
+                .into_iter()
+                .filter(|f| !features.split_whitespace().any(|e| e == *f))
+                .collect();
+
+            if missing.is_empty() {
+                checks.push(Check::new(
+                    "experimental features",
+                    Status::Pass,
+                    "nix-command and flakes are enabled",
+                ));
+            } else {
+                checks.push(Check::new(
+                    "experimental features",
+                    Status::Fail,
+                    format!("{} not enabled", missing.join(" and ")),
+                ));
+            }
+        }
+    }
+}
+
+/// Extract the experimental-features line of `nix show-config`
+fn parse_features(config: &str) -> Option<String> {
+    config.lines().find_map(|line| {
+        let (key, value) = line.split_once('=')?;
+        (key.trim() == "experimental-features").then(|| value.trim().to_owned())
+    })
+}
+
+#[test]
+fn test_parse_features() {
+    let config = "cores = 0\nexperimental-features = flakes nix-command\nmax-jobs = 8\n";
+    assert_eq!(
+        parse_features(config).as_deref(),
+        Some("flakes nix-command")
+    );
+    assert_eq!(parse_features("cores = 0\n"), None);
+}
+
+fn check_binary(checks: &mut Vec<Check>, name: &'static str, missing: Status, why: &str) {
+    match util::find_in_path(name) {
+        None => checks.push(Check::new(
+            name,
+            missing,
+            format!("not found in PATH, {why}"),
+        )),
+        Some(path) => {
+            let version = output(name, &["--version"])
+                .and_then(|v| v.lines().next().map(str::to_owned))
+                .unwrap_or_else(|| path.display().to_string());
+            checks.push(Check::new(name, Status::Pass, version));
+        }
+    }
+}
+
+fn check_env(checks: &mut Vec<Check>) {
+    if std::env::var_os("FLAKE").is_some() {
+        checks.push(Check::new(
+            "environment",
+            Status::Warn,
+            "FLAKE is set, nh now uses NH_FLAKE instead",
+        ));
+    } else {
+        checks.push(Check::new(
+            "environment",
+            Status::Pass,
+            "no deprecated variables set",
+        ));
+    }
+}
+
+fn check_flake(checks: &mut Vec<Check>) {
+    let Ok(flake) = std::env::var("NH_FLAKE") else {
+        checks.push(Check::new(
+            "flake",
+            Status::Pass,
+            "NH_FLAKE is not set, installables must be given explicitly",
+        ));
+        return;
+    };
+
+    // The same resolution as rebuilds, so that what they accept is reported as working
+    let reference = match flake_ref::resolve(flake.split('#').next().unwrap_or_default()) {
+        Ok(reference) => reference,
+        Err(err) => {
+            checks.push(Check::new("flake", Status::Fail, format!("{err}")));
+            return;
+        }
+    };
+
+    let dirty = flake_ref::local_dir(&reference).is_some_and(|local| {
+        output(
+            "git",
+            &["-C", &local.to_string_lossy(), "status", "--porcelain"],
+        )
+        .is_some_and(|status| !status.is_empty())
+    });
+
+    if output("nix", &["flake", "metadata", "--json", &reference]).is_none() {
+        checks.push(Check::new(
+            "flake",
+            Status::Fail,
+            format!("NH_FLAKE={flake} couldn't be resolved by nix flake metadata"),
+        ));
+    } else if dirty {
+        checks.push(Check::new(
+            "flake",
+            Status::Warn,
+            format!(
+                "NH_FLAKE={flake} resolves, but has uncommitted changes, untracked files are invisible to nix"
+            ),
+        ));
+    } else {
+        checks.push(Check::new(
+            "flake",
+            Status::Pass,
+            format!("NH_FLAKE={flake} resolves"),
+        ));
+    }
+}
+
+fn check_profiles(checks: &mut Vec<Check>) {
+    let system = Path::new("/nix/var/nix/profiles/system");
+    if system.exists() {
+        checks.push(Check::new(
+            "system profile",
+            Status::Pass,
+            system.display().to_string(),
+        ));
+    } else {
+        checks.push(Check::new(
+            "system profile",
+            Status::Warn,
+            format!("{} doesn't exist, nh os won't work", system.display()),
+        ));
+    }
+
+    let mut home_profiles = Vec::new();
+    if let Ok(user) = std::env::var("USER") {
+        home_profiles.push(
+            PathBuf::from("/nix/var/nix/profiles/per-user")
+                .join(user)
+                .join("home-manager"),
+        );
+    }
+    if let Ok(home) = std::env::var("HOME") {
+        home_profiles.push(PathBuf::from(home).join(".local/state/nix/profiles/home-manager"));
+    }
+
+    match home_profiles.iter().find(|p| p.exists()) {
+        Some(profile) => checks.push(Check::new(
+            "home-manager profile",
+            Status::Pass,
+            profile.display().to_string(),
+        )),
+        None => checks.push(Check::new(
+            "home-manager profile",
+            Status::Warn,
+            "not found, nh home won't be able to compare generations",
+        )),
+    }
+}
+
+fn check_gcroots(checks: &mut Vec<Check>) {
+    let mut dirs = vec![PathBuf::from("/nix/var/nix/gcroots/auto")];
+    if let Ok(user) = std::env::var("USER") {
+        dirs.push(PathBuf::from("/nix/var/nix/gcroots/per-user").join(user));
+    }
+
+    for dir in dirs {
+        let access = faccessat(
+            None,
+            &dir,
+            AccessFlags::R_OK | AccessFlags::W_OK,
+            AtFlags::empty(),
+        );
+        match access {
+            Ok(()) => checks.push(Check::new(
+                "gcroots",
+                Status::Pass,
+                format!("{} is writable", dir.display()),
+            )),
+            Err(Errno::ENOENT) => checks.push(Check::new(
+                "gcroots",
+                Status::Warn,
+                format!("{} doesn't exist", dir.display()),
+            )),
+            Err(errno) => checks.push(Check::new(
+                "gcroots",
+                Status::Warn,
+                format!(
+                    "{} is not writable ({errno}), nh clean needs to run as root",
+                    dir.display()
+                ),
+            )),
+        }
+    }
+}
+
+fn check_specialisation(checks: &mut Vec<Check>) {
+    let available = std::fs::read_dir("/run/current-system/specialisation")
+        .map(|entries| entries.count())
+        .unwrap_or(0);
+
+    match std::fs::read_to_string(SPEC_LOCATION) {
+        Ok(spec) => checks.push(Check::new(
+            "specialisation",
+            Status::Pass,
+            format!("running specialisation {}", spec.trim()),
+        )),
+        Err(_) if available > 0 => checks.push(Check::new(
+            "specialisation",
+            Status::Warn,
+            format!(
+                "{available} specialisation(s) available but {SPEC_LOCATION} doesn't exist, nh can't detect which one is running"
+            ),
+        )),
+        Err(_) => checks.push(Check::new(
+            "specialisation",
+            Status::Pass,
+            "no specialisations in use",
+        )),
+    }
+}
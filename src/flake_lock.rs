@@ -0,0 +1,655 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::Context;
+use owo_colors::OwoColorize;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::commands::Command;
+use crate::flake_ref;
+use crate::installable::Installable;
+use crate::json;
+use crate::util;
+use crate::Result;
+
+/// The parts of a `flake.lock` nh cares about
+#[derive(Debug, Default, Deserialize)]
+pub struct FlakeLock {
+    nodes: HashMap<String, Node>,
+    root: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Node {
+    #[serde(default)]
+    inputs: BTreeMap<String, InputRef>,
+    locked: Option<Locked>,
+    original: Option<Original>,
+}
+
+/// The reference an input was locked from, as written in flake.nix
+#[derive(Debug, Deserialize)]
+struct Original {
+    /// Branch or tag, like `nixos-24.05`
+    #[serde(rename = "ref")]
+    reference: Option<String>,
+}
+
+/// Either the name of a node, or the path of an input it follows
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum InputRef {
+    Node(String),
+    Follows(Vec<String>),
+}
+
+/// The locked source of an input
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Locked {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub owner: Option<String>,
+    pub repo: Option<String>,
+    pub host: Option<String>,
+    pub url: Option<String>,
+    pub path: Option<String>,
+    pub rev: Option<String>,
+    pub last_modified: Option<i64>,
+    pub nar_hash: Option<String>,
+}
+
+impl Locked {
+    /// Flake reference of the locked source, like `github:NixOS/nixpkgs/<rev>`
+    pub fn reference(&self) -> String {
+        let rev = self.rev.as_deref();
+        match (self.kind.as_str(), &self.owner, &self.repo) {
+            (kind @ ("github" | "gitlab" | "sourcehut"), Some(owner), Some(repo)) => {
+                let mut res = format!("{kind}:{owner}/{repo}");
+                if let Some(rev) = rev {
+                    res.push('/');
+                    res.push_str(rev);
+                }
+                if let Some(host) = &self.host {
+                    res.push_str("?host=");
+                    res.push_str(host);
+                }
+                res
+            }
+            (kind, _, _) => {
+                let location = self
+                    .url
+                    .as_deref()
+                    .or(self.path.as_deref())
+                    .unwrap_or_default();
+                match (kind, rev) {
+                    ("git" | "hg", Some(rev)) => format!("{kind}+{location}?rev={rev}"),
+                    ("git" | "hg", None) => format!("{kind}+{location}"),
+                    _ => format!("{kind}:{location}"),
+                }
+            }
+        }
+    }
+
+    /// Date of the last commit, as YYYY-MM-DD
+    pub fn date(&self) -> Option<String> {
+        let date = DateTime::from_timestamp(self.last_modified?, 0)?;
+        Some(date.format("%Y-%m-%d").to_string())
+    }
+}
+
+/// An input of the lock file, either locked or following another one
+#[derive(Debug)]
+pub struct Entry<'a> {
+    /// Path of the input, like `home-manager/nixpkgs`
+    pub input: String,
+    pub locked: Option<&'a Locked>,
+    /// Path of the input this one follows
+    pub follows: Option<String>,
+}
+
+/// An input whose locked source differs between two lock files
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    /// Path of the input, like `home-manager/nixpkgs`
+    pub input: String,
+    pub old: Option<Locked>,
+    pub new: Option<Locked>,
+}
+
+impl FlakeLock {
+    pub fn parse(content: &str) -> Result<Self> {
+        Ok(serde_json::from_str(content)?)
+    }
+
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path).wrap_err(format!("Reading {}", path.display()))?;
+        Self::parse(&content).wrap_err(format!("Parsing {}", path.display()))
+    }
+
+    /// The locked source of a direct input of the flake, with the branch or tag it tracks
+    pub fn input(&self, name: &str) -> Option<(&Locked, Option<&str>)> {
+        let InputRef::Node(target) = self.nodes.get(&self.root)?.inputs.get(name)? else {
+            return None;
+        };
+        let node = self.nodes.get(target)?;
+        let reference = node.original.as_ref().and_then(|o| o.reference.as_deref());
+        Some((node.locked.as_ref()?, reference))
+    }
+
+    /// All the locked inputs, by their path from the root, skipping the ones that follow another
+    pub fn inputs(&self) -> BTreeMap<String, &Locked> {
+        let mut res = BTreeMap::new();
+        self.collect_inputs(&self.root, "", &mut res, 0);
+        res
+    }
+
+    fn collect_inputs<'a>(
+        &'a self,
+        node: &str,
+        prefix: &str,
+        res: &mut BTreeMap<String, &'a Locked>,
+        depth: usize,
+    ) {
+        // Lock files can't be cyclic, but don't trust them blindly
+        if depth > 16 {
+            return;
+        }
+
+        let Some(node) = self.nodes.get(node) else {
+            return;
+        };
+
+        for (name, input) in &node.inputs {
+            let InputRef::Node(target) = input else {
+                continue;
+            };
+            let path = format!("{prefix}{name}");
+            if let Some(locked) = self.nodes.get(target).and_then(|n| n.locked.as_ref()) {
+                res.insert(path.clone(), locked);
+            }
+            self.collect_inputs(target, &format!("{path}/"), res, depth + 1);
+        }
+    }
+
+    /// Every input by its path from the root, including the ones that follow another
+    pub fn entries(&self) -> Vec<Entry<'_>> {
+        let mut res = Vec::new();
+        self.collect_entries(&self.root, "", &mut res, 0);
+        res
+    }
+
+    fn collect_entries<'a>(
+        &'a self,
+        node: &str,
+        prefix: &str,
+        res: &mut Vec<Entry<'a>>,
+        depth: usize,
+    ) {
+        if depth > 16 {
+            return;
+        }
+
+        let Some(node) = self.nodes.get(node) else {
+            return;
+        };
+
+        for (name, input) in &node.inputs {
+            let path = format!("{prefix}{name}");
+            match input {
+                InputRef::Node(target) => {
+                    res.push(Entry {
+                        input: path.clone(),
+                        locked: self.nodes.get(target).and_then(|n| n.locked.as_ref()),
+                        follows: None,
+                    });
+                    self.collect_entries(target, &format!("{path}/"), res, depth + 1);
+                }
+                InputRef::Follows(follows) => res.push(Entry {
+                    input: path,
+                    locked: None,
+                    follows: Some(follows.join("/")),
+                }),
+            }
+        }
+    }
+
+    /// Direct inputs matching any of the globs whose last commit is older than `max_age` seconds,
+    /// with their age
+    pub fn stale(&self, patterns: &[String], max_age: i64, now: i64) -> Vec<(String, i64)> {
+        let Some(root) = self.nodes.get(&self.root) else {
+            return vec![];
+        };
+
+        root.inputs
+            .iter()
+            .filter(|(name, _)| patterns.iter().any(|p| util::glob_match(p, name)))
+            .filter_map(|(name, input)| {
+                let InputRef::Node(target) = input else {
+                    return None;
+                };
+                let age = now - self.nodes.get(target)?.locked.as_ref()?.last_modified?;
+                (age > max_age).then(|| (name.clone(), age))
+            })
+            .collect()
+    }
+
+    /// The inputs that were added, removed or moved to another revision
+    pub fn diff(&self, new: &FlakeLock) -> Vec<Change> {
+        let old_inputs = self.inputs();
+        let new_inputs = new.inputs();
+
+        let mut names: Vec<&String> = old_inputs.keys().chain(new_inputs.keys()).collect();
+        names.sort();
+        names.dedup();
+
+        names
+            .into_iter()
+            .filter_map(|name| {
+                let old = old_inputs.get(name).copied();
+                let new = new_inputs.get(name).copied();
+                let changed = match (old, new) {
+                    (Some(old), Some(new)) => old.rev != new.rev || old.nar_hash != new.nar_hash,
+                    _ => true,
+                };
+                changed.then(|| Change {
+                    input: name.clone(),
+                    old: old.cloned(),
+                    new: new.cloned(),
+                })
+            })
+            .collect()
+    }
+}
+
+impl Change {
+    /// Seconds between the old and the new lastModified, negative if the input went back in time
+    pub fn age_jump(&self) -> Option<i64> {
+        let old = self.old.as_ref()?.last_modified?;
+        let new = self.new.as_ref()?.last_modified?;
+        Some(new - old)
+    }
+
+    /// Web page comparing both revisions, for inputs hosted on GitHub or GitLab
+    pub fn compare_url(&self) -> Option<String> {
+        let old = self.old.as_ref()?;
+        let new = self.new.as_ref()?;
+        if old.kind != new.kind || old.owner != new.owner || old.repo != new.repo {
+            return None;
+        }
+
+        let (owner, repo) = (new.owner.as_ref()?, new.repo.as_ref()?);
+        let (old_rev, new_rev) = (old.rev.as_ref()?, new.rev.as_ref()?);
+        match new.kind.as_str() {
+            "github" => Some(format!(
+                "https://{}/{owner}/{repo}/compare/{old_rev}...{new_rev}",
+                new.host.as_deref().unwrap_or("github.com")
+            )),
+            "gitlab" => Some(format!(
+                "https://{}/{owner}/{repo}/-/compare/{old_rev}...{new_rev}",
+                new.host.as_deref().unwrap_or("gitlab.com")
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// A locked revision, as shown in the update summary
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RevisionSummary {
+    pub reference: String,
+    pub rev: Option<String>,
+    pub last_modified: Option<i64>,
+    pub date: Option<String>,
+}
+
+/// What happened to an input during an update, as shown in the update summary
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChangeSummary {
+    pub input: String,
+    pub old: Option<RevisionSummary>,
+    pub new: Option<RevisionSummary>,
+    /// In seconds
+    pub age_jump: Option<i64>,
+    pub compare_url: Option<String>,
+}
+
+impl From<&Locked> for RevisionSummary {
+    fn from(locked: &Locked) -> Self {
+        Self {
+            reference: locked.reference(),
+            rev: locked.rev.clone(),
+            last_modified: locked.last_modified,
+            date: locked.date(),
+        }
+    }
+}
+
+impl From<&Change> for ChangeSummary {
+    fn from(change: &Change) -> Self {
+        Self {
+            input: change.input.clone(),
+            old: change.old.as_ref().map(Into::into),
+            new: change.new.as_ref().map(Into::into),
+            age_jump: change.age_jump(),
+            compare_url: change.compare_url(),
+        }
+    }
+}
+
+const HOUR: i64 = 60 * 60;
+const DAY: i64 = 24 * HOUR;
+
+/// Human readable length of a period, like `3 days`
+fn format_age(seconds: i64) -> String {
+    let (count, unit) = if seconds >= DAY {
+        (seconds / DAY, "day")
+    } else if seconds >= HOUR {
+        (seconds / HOUR, "hour")
+    } else {
+        return String::from("less than an hour");
+    };
+
+    let plural = if count == 1 { "" } else { "s" };
+    format!("{count} {unit}{plural}")
+}
+
+/// Human readable length of an age jump, like `3 days newer`
+fn format_age_jump(seconds: i64) -> String {
+    if seconds.abs() < HOUR {
+        return String::from("less than an hour apart");
+    }
+
+    let direction = if seconds < 0 { "older" } else { "newer" };
+    format!("{} {direction}", format_age(seconds.abs()))
+}
+
+#[test]
+fn test_format_age_jump() {
+    assert_eq!(format_age_jump(90), "less than an hour apart");
+    assert_eq!(format_age_jump(2 * 60 * 60), "2 hours newer");
+    assert_eq!(format_age_jump(-24 * 60 * 60), "1 day older");
+}
+
+fn short_rev(locked: &Locked) -> String {
+    let rev = match &locked.rev {
+        Some(rev) => rev.chars().take(7).collect(),
+        None => locked.reference(),
+    };
+    match locked.date() {
+        Some(date) => format!("{rev} ({date})"),
+        None => rev,
+    }
+}
+
+/// The changed inputs, one per line with their compare URL below
+pub fn summary(changes: &[Change]) -> String {
+    let width = changes.iter().map(|c| c.input.len()).max().unwrap_or(0);
+
+    let mut res = String::from("\n");
+    let _ = writeln!(
+        res,
+        "{}",
+        format!("Updated flake inputs ({})", changes.len())
+            .blue()
+            .bold()
+    );
+    for change in changes {
+        let description = match (&change.old, &change.new) {
+            (Some(old), Some(new)) => {
+                let mut res = format!("{} → {}", short_rev(old), short_rev(new));
+                if let Some(jump) = change.age_jump() {
+                    res.push_str(&format!(", {}", format_age_jump(jump)));
+                }
+                res
+            }
+            (None, Some(new)) => format!("added at {}", short_rev(new)),
+            (Some(_), None) => String::from("removed"),
+            (None, None) => continue,
+        };
+
+        let _ = writeln!(res, "- {:<width$}  {}", change.input, description);
+        if let Some(url) = change.compare_url() {
+            let _ = writeln!(res, "  {:<width$}  {}", "", url.dimmed());
+        }
+    }
+    res.push('\n');
+    res
+}
+
+fn describe(locked: &Locked) -> String {
+    match locked.date() {
+        Some(date) => format!("'{}' ({date})", locked.reference()),
+        None => format!("'{}'", locked.reference()),
+    }
+}
+
+/// Commit message listing the changed inputs, in the same format as `nix flake update --commit-lock-file`
+pub fn commit_message(changes: &[Change]) -> String {
+    let mut res = String::from("flake.lock: Update\n\nFlake lock file updates:\n");
+
+    for change in changes {
+        res.push('\n');
+        let _ = match (&change.old, &change.new) {
+            (Some(old), Some(new)) => writeln!(
+                res,
+                "• Updated input '{}':\n    {}\n  → {}",
+                change.input,
+                describe(old),
+                describe(new)
+            ),
+            (None, Some(new)) => writeln!(
+                res,
+                "• Added input '{}':\n    {}",
+                change.input,
+                describe(new)
+            ),
+            (Some(_), None) => writeln!(res, "• Removed input '{}'", change.input),
+            (None, None) => Ok(()),
+        };
+    }
+
+    res
+}
+
+/// Print every input of the lock file with its revision and age
+pub fn print_inputs(lock: &FlakeLock, now: i64) {
+    let entries = lock.entries();
+    let width = entries
+        .iter()
+        .map(|e| e.input.len())
+        .max()
+        .unwrap_or(0)
+        .max("Input".len());
+
+    println!(
+        "{}",
+        format!(
+            "{:<width$}  {:<8} {:<13} {:<10} Follows",
+            "Input", "Revision", "Last modified", "Age"
+        )
+        .bold()
+    );
+    for entry in entries {
+        let locked = entry.locked;
+        let rev: String = locked
+            .and_then(|l| l.rev.as_deref())
+            .map(|r| r.chars().take(7).collect())
+            .unwrap_or_default();
+        let date = locked.and_then(Locked::date).unwrap_or_default();
+        let age = locked
+            .and_then(|l| l.last_modified)
+            .map(|t| format_age(now - t))
+            .unwrap_or_default();
+
+        println!(
+            "{:<width$}  {:<8} {:<13} {:<10} {}",
+            entry.input,
+            rev,
+            date,
+            age,
+            entry.follows.as_deref().unwrap_or_default()
+        );
+    }
+}
+
+/// The lock file of a flake, read from disk for local flakes or from `nix flake metadata` otherwise
+pub fn for_reference(reference: &str) -> Result<FlakeLock> {
+    if let Some(dir) = flake_ref::local_dir(reference) {
+        return FlakeLock::read(dir.join("flake.lock"));
+    }
+
+    let output = Command::new("nix")
+        .args(["flake", "metadata", "--json", reference])
+        .run_capture()
+        .wrap_err(format!("Getting the lock file of {reference}"))?
+        .unwrap_or_default();
+    let metadata: serde_json::Value =
+        serde_json::from_str(&output).wrap_err("Parsing nix flake metadata")?;
+    let locks = json::Value::new(&metadata).get("locks")?.inner.clone();
+
+    Ok(serde_json::from_value(locks)?)
+}
+
+/// Warn about the inputs of a local flake matching any of the globs that weren't updated for
+/// longer than `max_age`
+pub fn warn_stale(installable: &Installable, patterns: &[String], max_age: Duration) -> Result<()> {
+    let Installable::Flake { reference, .. } = installable else {
+        return Ok(());
+    };
+    let Some(dir) = flake_ref::local_dir(reference) else {
+        return Ok(());
+    };
+    let path = dir.join("flake.lock");
+    if !path.exists() {
+        return Ok(());
+    }
+
+    let lock = FlakeLock::read(path)?;
+    let now = Utc::now().timestamp();
+    for (input, age) in lock.stale(patterns, max_age.as_secs() as i64, now) {
+        warn!(
+            "Flake input {input} was last updated {} ago, consider running with --update",
+            format_age(age)
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+const TEST_LOCK: &str = r#"{
+  "nodes": {
+    "home-manager": {
+      "inputs": { "nixpkgs": ["nixpkgs"] },
+      "locked": {
+        "lastModified": 1700000000,
+        "narHash": "sha256-hm",
+        "owner": "nix-community",
+        "repo": "home-manager",
+        "rev": "1111111111111111111111111111111111111111",
+        "type": "github"
+      }
+    },
+    "nixpkgs": {
+      "locked": {
+        "lastModified": 1700000000,
+        "narHash": "sha256-old",
+        "owner": "NixOS",
+        "repo": "nixpkgs",
+        "rev": "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa",
+        "type": "github"
+      },
+      "original": {
+        "owner": "NixOS",
+        "ref": "nixos-24.05",
+        "repo": "nixpkgs",
+        "type": "github"
+      }
+    },
+    "root": {
+      "inputs": { "home-manager": "home-manager", "nixpkgs": "nixpkgs" }
+    }
+  },
+  "root": "root",
+  "version": 7
+}"#;
+
+#[test]
+fn test_flake_lock_diff() {
+    let old = FlakeLock::parse(TEST_LOCK).unwrap();
+    let new = FlakeLock::parse(
+        &TEST_LOCK
+            .replace("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa", "bbbbbbb")
+            .replace("sha256-old", "sha256-new")
+            .replace(
+                r#""lastModified": 1700000000,
+        "narHash": "sha256-new""#,
+                r#""lastModified": 1710000000,
+        "narHash": "sha256-new""#,
+            ),
+    )
+    .unwrap();
+
+    assert_eq!(old.inputs().len(), 2);
+    assert!(old.diff(&old).is_empty());
+
+    let changes = old.diff(&new);
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].input, "nixpkgs");
+    assert_eq!(
+        changes[0].new.as_ref().unwrap().reference(),
+        "github:NixOS/nixpkgs/bbbbbbb"
+    );
+
+    assert_eq!(changes[0].age_jump(), Some(10_000_000));
+    assert_eq!(
+        changes[0].compare_url().as_deref(),
+        Some("https://github.com/NixOS/nixpkgs/compare/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa...bbbbbbb")
+    );
+
+    let message = commit_message(&changes);
+    assert!(message.contains("• Updated input 'nixpkgs':"));
+    assert!(message.contains("(2023-11-14)\n  → 'github:NixOS/nixpkgs/bbbbbbb' (2024-03-09)"));
+}
+
+#[test]
+fn test_flake_lock_entries() {
+    let lock = FlakeLock::parse(TEST_LOCK).unwrap();
+
+    let entries = lock.entries();
+    let follows: Vec<_> = entries
+        .iter()
+        .map(|e| (e.input.as_str(), e.follows.as_deref()))
+        .collect();
+    assert_eq!(
+        follows,
+        [
+            ("home-manager", None),
+            ("home-manager/nixpkgs", Some("nixpkgs")),
+            ("nixpkgs", None)
+        ]
+    );
+
+    let day = 24 * 60 * 60;
+    let nixpkgs = vec![String::from("nixpkgs")];
+    assert!(lock.stale(&nixpkgs, 30 * day, 1700000000 + day).is_empty());
+    assert_eq!(
+        lock.stale(&nixpkgs, 30 * day, 1700000000 + 40 * day),
+        [(String::from("nixpkgs"), 40 * day)]
+    );
+
+    let (locked, reference) = lock.input("nixpkgs").unwrap();
+    assert_eq!(locked.owner.as_deref(), Some("NixOS"));
+    assert_eq!(reference, Some("nixos-24.05"));
+    assert_eq!(lock.input("home-manager").unwrap().1, None);
+    assert!(lock.input("missing").is_none());
+}
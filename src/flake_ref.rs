@@ -0,0 +1,497 @@
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::UNIX_EPOCH;
+
+use color_eyre::eyre::{bail, eyre, Context};
+use color_eyre::Result;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::installable::{join_attribute, Installable, OutputsSpec};
+use crate::util;
+
+/// How nix interprets a flake reference
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    /// A local directory, like `.`, `~/config` or `path:/etc/nixos`
+    Path,
+    /// A name looked up in the flake registry, like `nixpkgs` or `nixpkgs/nixos-24.05`
+    Indirect,
+    /// Anything with a scheme, including shorthands like `github:owner/repo`
+    Url,
+}
+
+fn classify(reference: &str) -> Kind {
+    let indirect =
+        Regex::new(r"^(flake:)?[a-zA-Z][a-zA-Z0-9_-]*(/[a-zA-Z0-9_.-]+(/[0-9a-f]{40})?)?(\?.*)?$")
+            .unwrap();
+
+    if reference.starts_with("path:")
+        || reference.starts_with("git+file://")
+        || reference.starts_with(['/', '.', '~'])
+    {
+        Kind::Path
+    } else if indirect.is_match(reference) {
+        Kind::Indirect
+    } else if reference.contains(':') {
+        Kind::Url
+    } else {
+        // Nix takes a relative path like `hosts/laptop`
+        Kind::Path
+    }
+}
+
+#[test]
+fn test_flake_ref_classify() {
+    for (reference, kind) in [
+        (".", Kind::Path),
+        ("~/config", Kind::Path),
+        ("/etc/nixos?dir=sub", Kind::Path),
+        ("path:./config", Kind::Path),
+        ("git+file:///etc/nixos", Kind::Path),
+        ("hosts/laptop/config", Kind::Path),
+        ("nixpkgs", Kind::Indirect),
+        ("nixpkgs/nixos-24.05", Kind::Indirect),
+        ("flake:my-config", Kind::Indirect),
+        ("github:NixOS/nixpkgs/nixos-24.05", Kind::Url),
+        ("git+https://example.com/config.git?ref=main", Kind::Url),
+    ] {
+        assert_eq!(classify(reference), kind, "{reference}");
+    }
+}
+
+/// Replace a leading `~` with the home directory
+fn expand_tilde(path: &str) -> Result<PathBuf> {
+    let Some(rest) = path.strip_prefix('~') else {
+        return Ok(PathBuf::from(path));
+    };
+    if !rest.is_empty() && !rest.starts_with('/') {
+        return Ok(PathBuf::from(path));
+    }
+
+    let home = std::env::var_os("HOME")
+        .ok_or_else(|| eyre!("Couldn't expand {path}, $HOME is not set"))?;
+    Ok(PathBuf::from(home).join(rest.trim_start_matches('/')))
+}
+
+/// Find the directory with the `flake.nix` for a path, looking up to the enclosing git
+/// repository like nix does
+fn flake_root(dir: &Path) -> Option<&Path> {
+    dir.ancestors().find_map(|dir| {
+        if dir.join("flake.nix").is_file() {
+            Some(Some(dir))
+        } else if dir.join(".git").exists() {
+            Some(None)
+        } else {
+            None
+        }
+    })?
+}
+
+/// Turn a flake reference into what nix should be given, making local paths absolute so
+/// that a typo fails before anything is evaluated
+pub fn resolve(reference: &str) -> Result<String> {
+    match classify(reference) {
+        Kind::Url => return Ok(reference.to_owned()),
+        Kind::Indirect => {
+            // A directory named like a registry entry is most likely what was meant
+            let local = Path::new(reference);
+            if !reference.contains(['?', ':']) && local.join("flake.nix").is_file() {
+                debug!(
+                    reference,
+                    "Using the local directory instead of the flake registry"
+                );
+            } else {
+                return Ok(reference.to_owned());
+            }
+        }
+        Kind::Path => {}
+    }
+
+    let (scheme, rest) = ["path:", "git+file://"]
+        .into_iter()
+        .find_map(|scheme| Some((scheme, reference.strip_prefix(scheme)?)))
+        .unwrap_or(("", reference));
+    let (path, query) = match rest.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (rest, None),
+    };
+
+    let path = expand_tilde(path)?;
+    let canonical = fs::canonicalize(&path).wrap_err(format!("Flake {reference} doesn't exist"))?;
+    if !canonical.is_dir() {
+        bail!("Flake {reference} is not a directory");
+    }
+
+    let mut res = format!("{scheme}{}", canonical.display());
+    if let Some(query) = query {
+        res.push('?');
+        res.push_str(query);
+    }
+    if local_dir(&res).is_none() {
+        bail!("Flake {reference} has no flake.nix");
+    }
+    Ok(res)
+}
+
+#[test]
+fn test_flake_ref_resolve() {
+    let dir = tempfile::tempdir().unwrap();
+    let dir = fs::canonicalize(dir.path()).unwrap();
+    fs::create_dir_all(dir.join("config/hosts")).unwrap();
+    fs::write(dir.join("config/flake.nix"), "{ outputs = _: { }; }").unwrap();
+
+    let flake = dir.join("config");
+    let flake = flake.to_str().unwrap();
+    assert_eq!(resolve(flake).unwrap(), flake);
+    assert_eq!(
+        resolve(&format!("path:{flake}/hosts/../?lastModified=1")).unwrap(),
+        format!("path:{flake}?lastModified=1")
+    );
+    // Nix looks for flake.nix in the parent directories
+    assert_eq!(
+        resolve(&format!("{flake}/hosts")).unwrap(),
+        format!("{flake}/hosts")
+    );
+
+    let root = dir.to_str().unwrap();
+    assert_eq!(
+        resolve(&format!("path:{root}?dir=config")).unwrap(),
+        format!("path:{root}?dir=config")
+    );
+    assert!(resolve(&format!("path:{root}?dir=missing")).is_err());
+
+    assert!(resolve(&format!("{flake}/missing")).is_err());
+    assert!(resolve(&format!("{flake}/flake.nix")).is_err());
+    assert!(resolve(dir.to_str().unwrap()).is_err());
+
+    for reference in ["nixpkgs/nixos-24.05", "github:NixOS/nixpkgs"] {
+        assert_eq!(resolve(reference).unwrap(), reference);
+    }
+}
+
+/// Directory of the flake a local reference points to, where its flake.nix and flake.lock
+/// are. That is the `?dir=` subdirectory if there is one, otherwise the closest parent
+/// with a flake.nix, like nix does.
+pub fn local_dir(reference: &str) -> Option<PathBuf> {
+    if classify(reference) != Kind::Path {
+        return None;
+    }
+
+    let rest = ["path:", "git+file://"]
+        .into_iter()
+        .find_map(|scheme| reference.strip_prefix(scheme))
+        .unwrap_or(reference);
+    let (path, query) = match rest.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (rest, None),
+    };
+    let path = expand_tilde(path).ok()?;
+
+    let subdir = query
+        .into_iter()
+        .flat_map(|query| query.split('&'))
+        .find_map(|param| param.strip_prefix("dir="));
+    match subdir {
+        Some(subdir) => {
+            let dir = path.join(subdir);
+            dir.join("flake.nix").is_file().then_some(dir)
+        }
+        None => flake_root(&path).map(Path::to_path_buf),
+    }
+}
+
+#[test]
+fn test_flake_ref_local_dir() {
+    let dir = tempfile::tempdir().unwrap();
+    let dir = fs::canonicalize(dir.path()).unwrap();
+    fs::create_dir_all(dir.join("repo/.git")).unwrap();
+    fs::create_dir_all(dir.join("repo/sub/hosts")).unwrap();
+    fs::write(dir.join("repo/sub/flake.nix"), "{ outputs = _: { }; }").unwrap();
+
+    let repo = dir.join("repo");
+    let sub = repo.join("sub");
+    let repo = repo.to_str().unwrap();
+    assert_eq!(
+        local_dir(&format!("path:{repo}?dir=sub")),
+        Some(sub.clone())
+    );
+    assert_eq!(
+        local_dir(&format!("git+file://{repo}?ref=main&dir=sub")),
+        Some(sub.clone())
+    );
+    assert_eq!(local_dir(&format!("{repo}/sub/hosts")), Some(sub.clone()));
+    assert_eq!(local_dir(sub.to_str().unwrap()), Some(sub.clone()));
+
+    // The walk stops at the repository, without a flake.nix there is no local flake
+    assert_eq!(local_dir(repo), None);
+    assert_eq!(local_dir("github:NixOS/nixpkgs"), None);
+}
+
+/// Names of the configurations a flake exposes under an attribute like `nixosConfigurations`
+pub fn configuration_names<I, S>(reference: &str, kind: &str, extra_args: I) -> Result<Vec<String>>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let installable = Installable::Flake {
+        reference: reference.to_owned(),
+        attribute: vec![kind.to_owned()],
+        outputs: OutputsSpec::Default,
+    };
+
+    let mut cmd = Command::new("nix");
+    cmd.args(["eval", "--json", "--apply", "builtins.attrNames"])
+        .args(extra_args)
+        .args(installable.to_args()?);
+    debug!(?cmd);
+
+    let output = cmd.output().wrap_err("Running nix eval")?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if stderr.contains("does not provide attribute") {
+            bail!("Flake {reference} doesn't have a {kind} attribute");
+        }
+        bail!("Couldn't evaluate flake {reference}:\n{}", stderr.trim());
+    }
+
+    serde_json::from_slice(&output.stdout).wrap_err(format!("Parsing the names of {kind}"))
+}
+
+const CACHE_FILE: &str = "configurations.json";
+
+/// Configuration names of a local flake from its last evaluation
+#[derive(Debug, Serialize, Deserialize)]
+struct CacheEntry {
+    /// Hash of the sources the names were evaluated with, see [`source_hash`]
+    source: String,
+    names: Vec<String>,
+}
+
+/// Most directory entries looked at for the Nix files of a flake, beyond which it isn't cached
+const MAX_SOURCE_ENTRIES: usize = 10_000;
+
+/// Hash of what the configuration names of a local flake depend on, which the cached names
+/// are valid for: the arguments of the evaluation, its lock file, and the modification time
+/// of each of its Nix files since a configuration can be renamed in any of them. A flake in
+/// the store never changes, so its files aren't looked at.
+fn source_hash(reference: &str, extra_args: &[OsString]) -> Option<String> {
+    let dir = local_dir(reference)?;
+    let mut parts: Vec<Vec<u8>> = extra_args
+        .iter()
+        .map(|arg| arg.as_encoded_bytes().to_vec())
+        .collect();
+    parts.push(dir.as_os_str().as_encoded_bytes().to_vec());
+    parts.push(fs::read(dir.join("flake.lock")).ok()?);
+    if dir.starts_with("/nix/store") {
+        return Some(util::sha256(parts));
+    }
+
+    let mut files = Vec::new();
+    let mut budget = MAX_SOURCE_ENTRIES;
+    if !nix_files(&dir, &mut files, &mut budget) {
+        debug!(?dir, "Too many files to cache the configuration names");
+        return None;
+    }
+    files.sort();
+    for path in files {
+        let metadata = fs::metadata(&path).ok()?;
+        let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        parts.push(
+            format!(
+                "{}:{}:{}",
+                path.display(),
+                metadata.len(),
+                modified.as_nanos()
+            )
+            .into_bytes(),
+        );
+    }
+
+    Some(util::sha256(parts))
+}
+
+/// The `.nix` files in a directory, leaving out hidden directories and symlinks like the
+/// `result` of builds. Returns false if there are more than `budget` entries to look at.
+fn nix_files(dir: &Path, res: &mut Vec<PathBuf>, budget: &mut usize) -> bool {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return true;
+    };
+    for entry in entries.flatten() {
+        let Some(left) = budget.checked_sub(1) else {
+            return false;
+        };
+        *budget = left;
+
+        let (path, Ok(kind)) = (entry.path(), entry.file_type()) else {
+            continue;
+        };
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        if kind.is_dir() {
+            if !nix_files(&path, res, budget) {
+                return false;
+            }
+        } else if kind.is_file() && path.extension() == Some(OsStr::new("nix")) {
+            res.push(path);
+        }
+    }
+    true
+}
+
+#[test]
+fn test_source_hash() {
+    let dir = tempfile::tempdir().unwrap();
+    let dir = fs::canonicalize(dir.path()).unwrap();
+    fs::create_dir_all(dir.join("hosts")).unwrap();
+    fs::write(dir.join("flake.nix"), "{ outputs = _: { }; }").unwrap();
+    fs::write(dir.join("flake.lock"), "{}").unwrap();
+    fs::write(dir.join("hosts/laptop.nix"), "{ }").unwrap();
+
+    let reference = dir.to_str().unwrap();
+    let hash = source_hash(reference, &[]).unwrap();
+    assert_eq!(source_hash(reference, &[]).unwrap(), hash);
+
+    // Evaluating with other inputs
+    let args = ["--override-input", "nixpkgs", "github:NixOS/nixpkgs"].map(OsString::from);
+    assert_ne!(source_hash(reference, &args).unwrap(), hash);
+
+    // Renaming a configuration in an imported file
+    fs::write(dir.join("hosts/laptop.nix"), "{ renamed = { }; }").unwrap();
+    assert_ne!(source_hash(reference, &[]).unwrap(), hash);
+
+    let mut files = Vec::new();
+    assert!(!nix_files(&dir, &mut files, &mut 2));
+}
+
+fn read_cache() -> Result<HashMap<String, CacheEntry>> {
+    let path = util::cache_dir()?.join(CACHE_FILE);
+    match fs::read_to_string(&path) {
+        Ok(s) => Ok(serde_json::from_str(&s)?),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(err) => Err(err).wrap_err(format!("Reading {}", path.display())),
+    }
+}
+
+fn write_cache(cache: &HashMap<String, CacheEntry>) -> Result<()> {
+    let path = util::cache_dir()?.join(CACHE_FILE);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&path, serde_json::to_string(cache)?)?;
+    Ok(())
+}
+
+/// Like [`configuration_names`], but reusing the names of the last evaluation of a local
+/// flake if its sources didn't change since and they include any of `wanted`. Otherwise
+/// a configuration added since then would be missed.
+pub fn find_configurations<I, S>(
+    reference: &str,
+    kind: &str,
+    wanted: &[String],
+    extra_args: I,
+) -> Result<Vec<String>>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let extra_args: Vec<OsString> = extra_args
+        .into_iter()
+        .map(|arg| arg.as_ref().to_owned())
+        .collect();
+    let key = format!("{reference}#{kind}");
+    let source = source_hash(reference, &extra_args);
+    let mut cache = read_cache().unwrap_or_else(|err| {
+        debug!(?err, "Ignoring the configuration cache");
+        HashMap::new()
+    });
+
+    if let (Some(source), Some(entry)) = (&source, cache.get(&key)) {
+        if entry.source == *source && wanted.iter().any(|w| entry.names.contains(w)) {
+            debug!(key, "Using cached configuration names");
+            return Ok(entry.names.clone());
+        }
+    }
+
+    let names = configuration_names(reference, kind, extra_args)?;
+    if let Some(source) = source {
+        cache.insert(
+            key,
+            CacheEntry {
+                source,
+                names: names.clone(),
+            },
+        );
+        if let Err(err) = write_cache(&cache) {
+            warn!(?err, "Failed to cache the configuration names");
+        }
+    }
+
+    Ok(names)
+}
+
+/// The configuration under `kind` to use for `name`, which is checked to exist so a typo
+/// fails before building. If it doesn't, the user can pick one of the configurations
+/// interactively.
+pub fn select_configuration<I, S>(
+    installable: &Installable,
+    kind: &str,
+    name: &str,
+    extra_args: I,
+) -> Result<String>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let Installable::Flake {
+        reference,
+        attribute,
+        ..
+    } = installable
+    else {
+        return Ok(name.to_owned());
+    };
+    // An explicitly selected attribute is up to the user
+    if !attribute.is_empty() {
+        return Ok(name.to_owned());
+    }
+
+    let names = find_configurations(reference, kind, &[name.to_owned()], extra_args)?;
+    if names.iter().any(|n| n == name) {
+        return Ok(name.to_owned());
+    }
+
+    let missing = format!(
+        "Flake {reference} doesn't have {kind}.{}",
+        join_attribute([name])?
+    );
+    if names.is_empty() {
+        bail!("{missing}, it has no configurations");
+    }
+    let suggestion = util::did_you_mean(name, names.iter().map(String::as_str));
+
+    if !std::io::stdin().is_terminal() {
+        let suggestion = suggestion
+            .map(|s| format!(", did you mean `{s}`?"))
+            .unwrap_or_default();
+        bail!("{missing}{suggestion}\nAvailable: {}", names.join(", "));
+    }
+
+    warn!("{missing}");
+    let default = suggestion
+        .and_then(|s| names.iter().position(|n| n == s))
+        .unwrap_or(0);
+    let choice = dialoguer::FuzzySelect::new()
+        .with_prompt("Which configuration do you want to use?")
+        .items(&names)
+        .default(default)
+        .interact()?;
+
+    Ok(names[choice].clone())
+}
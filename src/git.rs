@@ -0,0 +1,159 @@
+use std::path::Path;
+use std::process::Command;
+
+use color_eyre::eyre::{bail, Context};
+use tracing::{debug, warn};
+
+use crate::flake_ref;
+use crate::installable::Installable;
+use crate::Result;
+
+/// Run git in a repository and return its stdout, failing with its stderr if it exits with an error
+pub fn run<P: AsRef<Path>>(repo: P, args: &[&str]) -> Result<String> {
+    let mut cmd = Command::new("git");
+    cmd.arg("-C").arg(repo.as_ref()).args(args);
+    debug!(?cmd);
+
+    let output = cmd
+        .output()
+        .wrap_err(format!("Running git {}", args.join(" ")))?;
+
+    if !output.status.success() {
+        bail!(
+            "git {} failed: {}",
+            args.first().unwrap_or(&""),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Run git in a repository with its output shown to the user, returning whether it succeeded
+pub fn status<P: AsRef<Path>>(repo: P, args: &[&str]) -> Result<bool> {
+    let mut cmd = Command::new("git");
+    cmd.arg("-C").arg(repo.as_ref()).args(args);
+    debug!(?cmd);
+
+    let status = cmd
+        .status()
+        .wrap_err(format!("Running git {}", args.join(" ")))?;
+    Ok(status.success())
+}
+
+/// Files of a checkout that differ from its last commit
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Changes {
+    modified: Vec<String>,
+    untracked: Vec<String>,
+}
+
+/// Parse `git status --porcelain`, leaving out some paths, which like the ones of git are
+/// relative to the root of the repository
+fn parse_status(status: &str, ignore: &[&str]) -> Changes {
+    let mut res = Changes::default();
+
+    for line in status.lines() {
+        let Some((state, path)) = line.split_at_checked(3) else {
+            continue;
+        };
+        // Renames are shown as `old -> new`
+        let path = path.rsplit(" -> ").next().unwrap_or(path).to_owned();
+        if ignore.contains(&path.as_str()) {
+            continue;
+        }
+
+        if state.starts_with("??") {
+            res.untracked.push(path);
+        } else {
+            res.modified.push(path);
+        }
+    }
+
+    res
+}
+
+#[test]
+fn test_parse_status() {
+    let status = " M hosts/laptop.nix\nA  modules/new.nix\n?? secrets.nix\n M flake.lock\nR  a.nix -> b.nix\n";
+    assert_eq!(
+        parse_status(status, &["flake.lock"]),
+        Changes {
+            modified: vec![
+                String::from("hosts/laptop.nix"),
+                String::from("modules/new.nix"),
+                String::from("b.nix"),
+            ],
+            untracked: vec![String::from("secrets.nix")],
+        }
+    );
+
+    // The lock of a flake in a subdirectory
+    let status = " M sub/flake.lock\n M flake.lock\n";
+    assert_eq!(
+        parse_status(status, &["sub/flake.lock"]),
+        Changes {
+            modified: vec![String::from("flake.lock")],
+            untracked: vec![],
+        }
+    );
+}
+
+/// Warn if a local flake has uncommitted changes or unpushed commits, or refuse to go on if
+/// a clean checkout is required
+pub fn check_clean(installable: &Installable, require_clean: bool, updated: bool) -> Result<()> {
+    let Installable::Flake { reference, .. } = installable else {
+        return Ok(());
+    };
+    let Some(dir) = flake_ref::local_dir(reference) else {
+        return Ok(());
+    };
+    if run(&dir, &["rev-parse", "--is-inside-work-tree"]).is_err() {
+        debug!(?dir, "Flake is not in a git repository");
+        return Ok(());
+    }
+
+    // A lock file changed by --update is expected, wherever the flake is in the repository
+    let lock = format!(
+        "{}flake.lock",
+        run(&dir, &["rev-parse", "--show-prefix"])?.trim()
+    );
+    let ignore: &[&str] = if updated { &[&lock] } else { &[] };
+    let changes = parse_status(&run(&dir, &["status", "--porcelain"])?, ignore);
+
+    let mut problems = Vec::new();
+    if !changes.modified.is_empty() {
+        problems.push(format!(
+            "Uncommitted changes, the generation won't record a configurationRevision:\n  {}",
+            changes.modified.join("\n  ")
+        ));
+    }
+    if !changes.untracked.is_empty() {
+        problems.push(format!(
+            "Untracked files, which are invisible to the flake until they are added:\n  {}",
+            changes.untracked.join("\n  ")
+        ));
+    }
+
+    match run(&dir, &["rev-list", "--count", "@{upstream}..HEAD"]) {
+        Ok(count) => match count.trim() {
+            "0" => {}
+            count => problems.push(format!(
+                "{count} commit(s) not pushed to the upstream branch"
+            )),
+        },
+        Err(err) => debug!(%err, "Not checking for unpushed commits"),
+    }
+
+    if problems.is_empty() {
+        return Ok(());
+    }
+
+    let message = format!("{} is not clean\n{}", dir.display(), problems.join("\n"));
+    if require_clean {
+        bail!(message);
+    }
+    warn!("{message}");
+
+    Ok(())
+}
@@ -0,0 +1,304 @@
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use chrono::{DateTime, Local, Utc};
+use color_eyre::eyre::Context;
+use owo_colors::OwoColorize;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::progress::{format_duration, store_path_name, ActivityKind, BuildState};
+use crate::util;
+use crate::Result;
+
+const HISTORY_FILE: &str = "build-history.jsonl";
+
+/// Timings of a single `nh os` build, one line of the history file
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BuildRecord {
+    /// RFC3339 date of when the build finished
+    pub date: String,
+    pub hostname: String,
+    pub success: bool,
+    /// Wall time of the whole build, in seconds
+    pub duration: f64,
+    /// Whether nix's logs were parsed. Without them, as with `--no-nom`, what was built
+    /// or fetched is unknown.
+    pub logged: bool,
+    /// Derivations that were built locally, i.e. cache misses
+    pub builds: Vec<Timing>,
+    /// Derivations whose build failed
+    pub failures: Vec<Timing>,
+    /// Paths that were fetched from a substituter
+    pub downloads: Vec<Timing>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Timing {
+    pub name: String,
+    /// Duration in seconds
+    pub duration: f64,
+}
+
+impl BuildRecord {
+    /// A record with the timings of every build and download seen in nix's logs
+    pub fn new(hostname: &str, state: &BuildState, success: bool, duration: Duration) -> Self {
+        let failed = state.failed_derivations();
+        let timings = |kind, failures: bool| {
+            state
+                .finished
+                .iter()
+                .filter(|f| f.kind == kind && failed.contains(&f.path) == failures)
+                .map(|f| Timing {
+                    name: store_path_name(&f.path).to_owned(),
+                    duration: f.duration.as_secs_f64(),
+                })
+                .collect()
+        };
+
+        Self {
+            logged: true,
+            builds: timings(ActivityKind::Build, false),
+            failures: timings(ActivityKind::Build, true),
+            downloads: timings(ActivityKind::Substitute, false),
+            ..Self::unlogged(hostname, success, duration)
+        }
+    }
+
+    /// A record of a build whose logs weren't parsed, with only its outcome and duration
+    pub fn unlogged(hostname: &str, success: bool, duration: Duration) -> Self {
+        Self {
+            date: Utc::now().to_rfc3339(),
+            hostname: hostname.to_owned(),
+            success,
+            duration: duration.as_secs_f64(),
+            logged: false,
+            builds: vec![],
+            failures: vec![],
+            downloads: vec![],
+        }
+    }
+
+    /// Share of the realised paths that had to be built locally
+    fn cache_miss_ratio(&self) -> f64 {
+        let total = self.builds.len() + self.downloads.len();
+        if total == 0 {
+            0.0
+        } else {
+            self.builds.len() as f64 / total as f64
+        }
+    }
+}
+
+#[test]
+fn test_build_record_new() {
+    use crate::progress::LogLine;
+
+    let mut state = BuildState::default();
+    for line in [
+        r#"@nix {"action":"start","id":1,"type":105,"fields":["/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-hello.drv"]}"#,
+        r#"@nix {"action":"start","id":2,"type":105,"fields":["/nix/store/dddddddddddddddddddddddddddddddd-world.drv"]}"#,
+        r#"@nix {"action":"stop","id":1}"#,
+        r#"@nix {"action":"stop","id":2}"#,
+        r#"@nix {"action":"msg","level":0,"msg":"error: builder for '/nix/store/dddddddddddddddddddddddddddddddd-world.drv' failed"}"#,
+    ] {
+        if let LogLine::Event(event) = LogLine::parse(line) {
+            state.handle(event);
+        }
+    }
+
+    let record = BuildRecord::new("host", &state, false, Duration::from_secs(1));
+    assert!(record.logged);
+    assert_eq!(record.builds.len(), 1);
+    assert_eq!(record.builds[0].name, "hello");
+    assert_eq!(record.failures.len(), 1);
+    assert_eq!(record.failures[0].name, "world");
+
+    let record = BuildRecord::unlogged("host", true, Duration::from_secs(1));
+    assert!(!record.logged);
+    assert!(record.builds.is_empty());
+}
+
+fn history_file() -> Result<PathBuf> {
+    Ok(util::state_dir()?.join(HISTORY_FILE))
+}
+
+/// Append a record to the history file, warning instead of failing the build
+pub fn record(record: &BuildRecord) {
+    let res = (|| -> Result<()> {
+        let path = history_file()?;
+        debug!(?path, "Recording build history");
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+        writeln!(file, "{}", serde_json::to_string(record)?)?;
+        Ok(())
+    })();
+
+    if let Err(err) = res {
+        warn!(?err, "Failed to record build history");
+    }
+}
+
+/// Read the last `count` records for some hostname, oldest first
+pub fn load(hostname: &str, count: usize) -> Result<Vec<BuildRecord>> {
+    let path = history_file()?;
+    let file = match fs::File::open(&path) {
+        Ok(f) => f,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err).wrap_err(format!("Reading {}", path.display())),
+    };
+
+    let mut records: Vec<BuildRecord> = BufReader::new(file)
+        .lines()
+        .map_while(std::io::Result::ok)
+        .filter_map(|line| match serde_json::from_str(&line) {
+            Ok(record) => Some(record),
+            Err(err) => {
+                debug!(?err, "Skipping malformed history line");
+                None
+            }
+        })
+        .filter(|r: &BuildRecord| r.hostname == hostname)
+        .collect();
+
+    let skip = records.len().saturating_sub(count);
+    Ok(records.split_off(skip))
+}
+
+#[derive(Debug, Default)]
+struct Aggregate {
+    count: usize,
+    total: f64,
+    max: f64,
+}
+
+fn slowest(records: &[BuildRecord], top: usize) -> Vec<(&str, Aggregate)> {
+    let mut by_name: HashMap<&str, Aggregate> = HashMap::new();
+    for timing in records.iter().flat_map(|r| &r.builds) {
+        let agg = by_name.entry(&timing.name).or_default();
+        agg.count += 1;
+        agg.total += timing.duration;
+        agg.max = agg.max.max(timing.duration);
+    }
+
+    let mut res: Vec<_> = by_name.into_iter().collect();
+    res.sort_by(|(_, a), (_, b)| b.total.total_cmp(&a.total));
+    res.truncate(top);
+    res
+}
+
+#[test]
+fn test_slowest() {
+    let timing = |name: &str, duration| Timing {
+        name: name.to_owned(),
+        duration,
+    };
+    let record = |builds| BuildRecord {
+        date: String::new(),
+        hostname: String::from("host"),
+        success: true,
+        duration: 0.0,
+        logged: true,
+        builds,
+        failures: vec![],
+        downloads: vec![],
+    };
+
+    let records = vec![
+        record(vec![timing("linux-6.6", 600.0), timing("etc", 1.0)]),
+        record(vec![timing("linux-6.6", 400.0), timing("firefox", 700.0)]),
+    ];
+
+    let res = slowest(&records, 2);
+    assert_eq!(res.len(), 2);
+    assert_eq!(res[0].0, "linux-6.6");
+    assert_eq!(res[0].1.count, 2);
+    assert_eq!(res[0].1.max, 600.0);
+    assert_eq!(res[1].0, "firefox");
+
+    assert_eq!(records[0].cache_miss_ratio(), 1.0);
+}
+
+pub fn print_report(hostname: &str, records: &[BuildRecord], top: usize) {
+    if records.is_empty() {
+        println!("No builds recorded for {hostname} yet");
+        return;
+    }
+
+    let secs = |s: f64| format_duration(Duration::from_secs_f64(s));
+
+    println!(
+        "{}",
+        format!("Last {} build(s) of {}", records.len(), hostname).bold()
+    );
+    println!();
+    println!(
+        "{:<20} {:<9} {:<7} {:<7} {:<7} {:<12} Result",
+        "Date", "Duration", "Built", "Failed", "Fetched", "Cache misses"
+    );
+    for record in records {
+        let date = DateTime::parse_from_rfc3339(&record.date)
+            .map(|d| {
+                d.with_timezone(&Local)
+                    .format("%Y-%m-%d %H:%M:%S")
+                    .to_string()
+            })
+            .unwrap_or_else(|_| record.date.clone());
+        // Without the logs, what was built is unknown rather than nothing
+        let count = |timings: &[Timing]| {
+            if record.logged {
+                timings.len().to_string()
+            } else {
+                String::from("-")
+            }
+        };
+        let cache_misses = if record.logged {
+            format!("{:.1}%", record.cache_miss_ratio() * 100.0)
+        } else {
+            String::from("-")
+        };
+        println!(
+            "{:<20} {:<9} {:<7} {:<7} {:<7} {:<12} {}",
+            date,
+            secs(record.duration),
+            count(&record.builds),
+            count(&record.failures),
+            count(&record.downloads),
+            cache_misses,
+            if record.success {
+                "ok".green().to_string()
+            } else {
+                "failed".red().to_string()
+            }
+        );
+    }
+
+    let slowest = slowest(records, top);
+    if slowest.is_empty() {
+        return;
+    }
+
+    println!();
+    println!("{}", "Slowest derivations".bold());
+    println!();
+    println!(
+        "{:<40} {:<7} {:<9} {:<9} Max",
+        "Name", "Builds", "Total", "Average"
+    );
+    for (name, agg) in slowest {
+        println!(
+            "{:<40} {:<7} {:<9} {:<9} {}",
+            name,
+            agg.count,
+            secs(agg.total),
+            secs(agg.total / agg.count as f64),
+            secs(agg.max)
+        );
+    }
+}
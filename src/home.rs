@@ -1,15 +1,23 @@
 use std::env;
+use std::io::IsTerminal;
 use std::path::PathBuf;
 
-use color_eyre::eyre::bail;
+use color_eyre::eyre::{bail, eyre, Context};
 use color_eyre::Result;
 use tracing::{debug, info, warn};
 
 use crate::commands;
 use crate::commands::Command;
-use crate::installable::Installable;
-use crate::interface::{self, HomeRebuildArgs, HomeReplArgs, HomeSubcommand};
-use crate::update::{pull, update};
+use crate::config;
+use crate::flake_lock;
+use crate::flake_ref;
+use crate::git;
+use crate::hooks::{HookRunner, Phase, Rejected};
+use crate::installable::{join_attribute, Installable};
+use crate::interface::{self, CommitLock, HomeRebuildArgs, HomeReplArgs, HomeSubcommand};
+use crate::nix_info;
+use crate::notify::Notifier;
+use crate::update::{pull, update, LockUpdate};
 
 impl interface::HomeArgs {
     pub fn run(self) -> Result<()> {
@@ -17,8 +25,8 @@ impl interface::HomeArgs {
         match self.subcommand {
             HomeSubcommand::Switch(args) => args.rebuild(Switch),
             HomeSubcommand::Build(args) => {
-                if args.common.ask || args.common.dry {
-                    warn!("`--ask` and `--dry` have no effect for `nh home build`");
+                if args.common.ask {
+                    warn!("`--ask` has no effect for `nh home build`");
                 }
                 args.rebuild(Build)
             }
@@ -34,19 +42,87 @@ enum HomeRebuildVariant {
 }
 
 impl HomeRebuildArgs {
-    fn rebuild(self, variant: HomeRebuildVariant) -> Result<()> {
+    fn rebuild(mut self, variant: HomeRebuildVariant) -> Result<()> {
+        nix_info::supported()?;
+        let config = config::get()?;
+        self.extra_args
+            .splice(0..0, config.rebuild.extra_args.iter().cloned());
+
+        let variant_name = format!("{variant:?}").to_lowercase();
+        let notifier = Notifier::new(self.common.notify, format!("nh home {variant_name}"));
+        let mut hooks = HookRunner::new(&config.hooks.home, &variant_name);
+
+        let mut lock = None;
+        let res = self.rebuild_steps(variant, config, &notifier, &mut hooks, &mut lock);
+        if let Some(lock) = &lock {
+            lock.finished(res.is_ok());
+        }
+        if let Err(err) = &res {
+            hooks.failed(err);
+        }
+        notifier.finished(&res);
+        res
+    }
+
+    fn rebuild_steps(
+        mut self,
+        variant: HomeRebuildVariant,
+        config: &config::Config,
+        notifier: &Notifier,
+        hooks: &mut HookRunner,
+        lock: &mut Option<LockUpdate>,
+    ) -> Result<()> {
         use HomeRebuildVariant::*;
 
-        if self.common.pull {
+        self.common.installable = self.common.installable.resolve()?;
+
+        if self.common.pull.pull {
             pull(
                 &self.common.installable,
-                self.update_args.update,
+                &self.common.pull,
+                self.update_args.enabled(),
                 self.common.dry,
             )?;
         }
 
-        if self.update_args.update {
-            update(&self.common.installable, self.update_args.update_input)?;
+        if self.update_args.enabled() {
+            *lock = update(
+                &self.common.installable,
+                &self.update_args,
+                false,
+                self.common.dry,
+            )?;
+        }
+
+        git::check_clean(
+            &self.common.installable,
+            self.common.require_clean,
+            self.update_args.enabled(),
+        )?;
+        let rebuild_config = &config.rebuild;
+        flake_lock::warn_stale(
+            &self.common.installable,
+            &rebuild_config.stale_inputs(),
+            rebuild_config.stale_after()?,
+        )?;
+
+        let toplevel = toplevel_for(
+            self.common.installable.clone(),
+            self.configuration.clone(),
+            true,
+            &self.extra_args,
+        )?;
+
+        if self.common.dry {
+            if self.common.ask {
+                warn!("--ask has no effect as dry run was requested");
+            }
+            commands::Build::new(toplevel)
+                .extra_args(&self.extra_args)
+                .message("Evaluating Home-Manager configuration (dry run)")
+                .dry_run()?
+                .print();
+            return Ok(());
         }
 
         let out_path: Box<dyn crate::util::MaybeTempPath> = match self.common.out_link {
@@ -59,25 +135,35 @@ impl HomeRebuildArgs {
 
         debug!(?out_path);
 
-        let toplevel = toplevel_for(self.common.installable.clone(), true, &self.extra_args)?;
+        hooks.run(Phase::PreBuild)?;
 
         commands::Build::new(toplevel)
             .extra_arg("--out-link")
             .extra_arg(out_path.get_path())
             .extra_args(&self.extra_args)
             .message("Building Home-Manager configuration")
-            .nom(!self.common.no_nom)
+            .progress(!self.common.no_nom)
             .run()?;
 
-        let prev_generation: Option<PathBuf> = [
-            PathBuf::from("/nix/var/nix/profiles/per-user")
-                .join(env::var("USER").expect("Couldn't get username"))
-                .join("home-manager"),
-            PathBuf::from(env::var("HOME").expect("Couldn't get home directory"))
-                .join(".local/state/nix/profiles/home-manager"),
-        ]
-        .into_iter()
-        .find(|next| next.exists());
+        if let Some(lock) = lock.as_mut() {
+            lock.reached(CommitLock::Build)?;
+        }
+
+        hooks.target(out_path.get_path());
+        hooks.run(Phase::PostBuild)?;
+
+        let mut profiles = Vec::new();
+        if let Ok(username) = username() {
+            profiles.push(
+                PathBuf::from("/nix/var/nix/profiles/per-user")
+                    .join(username)
+                    .join("home-manager"),
+            );
+        }
+        if let Some(home) = env::var_os("HOME") {
+            profiles.push(PathBuf::from(home).join(".local/state/nix/profiles/home-manager"));
+        }
+        let prev_generation = profiles.into_iter().find(|next| next.exists());
 
         debug!(?prev_generation);
 
@@ -90,22 +176,22 @@ impl HomeRebuildArgs {
                 .run()?;
         }
 
-        if self.common.dry || matches!(variant, Build) {
-            if self.common.ask {
-                warn!("--ask has no effect as dry run was requested");
-            }
+        if let Build = variant {
             return Ok(());
         }
 
         if self.common.ask {
             info!("Apply the config?");
+            notifier.confirmation();
             let confirmation = dialoguer::Confirm::new().default(false).interact()?;
 
             if !confirmation {
-                bail!("User rejected the new config");
+                bail!(Rejected);
             }
         }
 
+        hooks.run(Phase::PreActivate)?;
+
         if let Some(ext) = &self.backup_extension {
             info!("Using {} as the backup extension", ext);
             env::set_var("HOME_MANAGER_BACKUP_EXT", ext);
@@ -115,6 +201,12 @@ impl HomeRebuildArgs {
             .message("Activating configuration")
             .run()?;
 
+        hooks.run(Phase::PostActivate)?;
+
+        if let Some(lock) = lock.as_mut() {
+            lock.reached(CommitLock::Switch)?;
+        }
+
         // Make sure out_path is not accidentally dropped
         // https://docs.rs/tempfile/3.12.0/tempfile/index.html#early-drop-pitfall
         drop(out_path);
@@ -125,6 +217,7 @@ impl HomeRebuildArgs {
 
 fn toplevel_for<I, S>(
     installable: Installable,
+    configuration: Option<String>,
     push_drv: bool,
     extra_args: I,
 ) -> Result<Installable>
@@ -133,13 +226,6 @@ where
     S: AsRef<std::ffi::OsStr>,
 {
     let mut res = installable.clone();
-    let extra_args = {
-        let mut vec = Vec::new();
-        for elem in extra_args.into_iter() {
-            vec.push(elem.as_ref().to_owned());
-        }
-        vec
-    };
 
     let toplevel = ["config", "home", "activationPackage"]
         .into_iter()
@@ -149,74 +235,74 @@ where
         Installable::Flake {
             ref reference,
             ref mut attribute,
+            ..
         } => 'flake: {
             // If user explicitely selects some other attribute, don't push homeConfigurations
             if !attribute.is_empty() {
                 break 'flake;
             }
 
-            attribute.push(String::from("homeConfigurations"));
-
             // check for <user> and <user@hostname>
-            let username = std::env::var("USER").expect("Couldn't get username");
-            let hostname = hostname::get()
-                .expect("Couldn't get hostname")
-                .to_str()
-                .unwrap()
-                .to_string();
-
-            let mut tried = vec![];
-
-            for attr in [format!("{username}@{hostname}"), username.to_string()] {
-                let func = format!(r#" x: x ? "{}" "#, attr);
-                let res = commands::Command::new("nix")
-                    .arg("eval")
-                    .args(&extra_args)
-                    .arg("--apply")
-                    .arg(func)
-                    .args(
-                        (Installable::Flake {
-                            reference: reference.clone(),
-                            attribute: attribute.clone(),
-                        })
-                        .to_args(),
-                    )
-                    .run_capture()
-                    .expect("Checking home-manager output");
-
-                tried.push({
-                    let mut attribute = attribute.clone();
-                    attribute.push(attr.clone());
-                    attribute
-                });
-
-                match res.map(|s| s.trim().to_owned()).as_deref() {
-                    Some("true") => {
-                        attribute.push(attr.clone());
-                        if push_drv {
-                            attribute.extend(toplevel);
-                        }
-                        break 'flake;
-                    }
-                    _ => {
-                        continue;
-                    }
+            let candidates = match configuration {
+                Some(configuration) => vec![configuration],
+                None => {
+                    let username = username()?;
+                    let hostname = hostname::get().context("Failed to get hostname")?;
+                    vec![
+                        format!("{username}@{}", hostname.to_string_lossy()),
+                        username,
+                    ]
                 }
-            }
+            };
 
-            let tried_str = tried
-                .into_iter()
-                .map(|a| {
-                    let f = Installable::Flake {
-                        reference: reference.clone(),
-                        attribute: a,
-                    };
-                    f.to_args().join(" ")
-                })
-                .collect::<Vec<_>>()
-                .join(", ");
+            let names = flake_ref::find_configurations(
+                reference,
+                "homeConfigurations",
+                &candidates,
+                extra_args,
+            )?;
+            let found: Vec<&String> = candidates.iter().filter(|c| names.contains(c)).collect();
+
+            let name = match found[..] {
+                [] => {
+                    let tried_str = candidates
+                        .iter()
+                        .map(|c| join_attribute(["homeConfigurations", c]))
+                        .collect::<Result<Vec<_>>>()?
+                        .join(", ");
+                    bail!(
+                        "Flake {reference} has none of {tried_str}\nAvailable: {}",
+                        names.join(", ")
+                    );
+                }
+                [name] => name,
+                [first, ..] if !std::io::stdin().is_terminal() => {
+                    let others = found[1..]
+                        .iter()
+                        .map(|c| join_attribute(["homeConfigurations", c]))
+                        .collect::<Result<Vec<_>>>()?
+                        .join(", ");
+                    warn!(
+                        "Several configurations match, using {} over {others}",
+                        join_attribute(["homeConfigurations", first])?
+                    );
+                    first
+                }
+                _ => {
+                    let choice = dialoguer::Select::new()
+                        .with_prompt("Several configurations match, which one do you want to use?")
+                        .items(&found)
+                        .default(0)
+                        .interact()?;
+                    found[choice]
+                }
+            };
 
-            bail!("Couldn't find home-manager configuration, tried {tried_str}");
+            attribute.push(String::from("homeConfigurations"));
+            attribute.push(name.clone());
+            if push_drv {
+                attribute.extend(toplevel);
+            }
         }
         Installable::File {
             ref mut attribute, ..
@@ -232,19 +318,35 @@ where
                 attribute.extend(toplevel);
             }
         }
-        Installable::Store { .. } => {}
+        Installable::Store { .. } | Installable::Derivation { .. } => {}
     }
 
     Ok(res)
 }
 
+/// Name of the current user, for the default configuration and profile
+fn username() -> Result<String> {
+    if let Ok(user) = env::var("USER") {
+        return Ok(user);
+    }
+
+    uzers::get_current_username()
+        .and_then(|user| user.into_string().ok())
+        .ok_or_else(|| eyre!("Couldn't get the username, $USER is not set"))
+}
+
 impl HomeReplArgs {
     fn run(self) -> Result<()> {
-        let toplevel = toplevel_for(self.installable, false, &self.extra_args)?;
+        let toplevel = toplevel_for(
+            self.installable.resolve()?,
+            self.configuration,
+            false,
+            &self.extra_args,
+        )?;
 
         Command::new("nix")
             .arg("repl")
-            .args(toplevel.to_args())
+            .args(toplevel.to_args()?)
             .run()?;
 
         Ok(())
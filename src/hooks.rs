@@ -0,0 +1,130 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use color_eyre::eyre::{bail, Context};
+use thiserror::Error;
+use tracing::{debug, info, warn};
+
+use crate::config::Hooks;
+use crate::Result;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Phase {
+    PreBuild,
+    PostBuild,
+    PreActivate,
+    PostActivate,
+    OnFailure,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Phase::PreBuild => "pre_build",
+            Phase::PostBuild => "post_build",
+            Phase::PreActivate => "pre_activate",
+            Phase::PostActivate => "post_activate",
+            Phase::OnFailure => "on_failure",
+        })
+    }
+}
+
+/// The user declined to apply the new configuration, which is not a failure for the
+/// `on_failure` hooks
+#[derive(Debug, Error)]
+#[error("User rejected the new config")]
+pub struct Rejected;
+
+/// Runs the configured hooks of a rebuild, passing them what is known about it
+#[derive(Debug)]
+pub struct HookRunner<'h> {
+    hooks: &'h Hooks,
+    variant: String,
+    hostname: Option<String>,
+    target: Option<PathBuf>,
+    specialisation: Option<String>,
+}
+
+impl<'h> HookRunner<'h> {
+    pub fn new<S: AsRef<str>>(hooks: &'h Hooks, variant: S) -> Self {
+        Self {
+            hooks,
+            variant: variant.as_ref().to_owned(),
+            hostname: hostname::get()
+                .ok()
+                .map(|h| h.to_string_lossy().into_owned()),
+            target: None,
+            specialisation: None,
+        }
+    }
+
+    pub fn hostname<S: AsRef<str>>(&mut self, hostname: S) {
+        self.hostname = Some(hostname.as_ref().to_owned());
+    }
+
+    /// Set the built configuration, resolving the out-link to its store path
+    pub fn target<P: AsRef<Path>>(&mut self, target: P) {
+        let target = target.as_ref();
+        self.target = Some(
+            target
+                .canonicalize()
+                .unwrap_or_else(|_| target.to_path_buf()),
+        );
+    }
+
+    /// Set the specialisation that is activated from the built configuration
+    pub fn specialisation<S: AsRef<str>>(&mut self, specialisation: S) {
+        self.specialisation = Some(specialisation.as_ref().to_owned());
+    }
+
+    pub fn run(&self, phase: Phase) -> Result<()> {
+        let commands = match phase {
+            Phase::PreBuild => &self.hooks.pre_build,
+            Phase::PostBuild => &self.hooks.post_build,
+            Phase::PreActivate => &self.hooks.pre_activate,
+            Phase::PostActivate => &self.hooks.post_activate,
+            Phase::OnFailure => &self.hooks.on_failure,
+        };
+
+        for command in commands {
+            info!("Running {phase} hook: {command}");
+
+            let mut cmd = Command::new("sh");
+            cmd.arg("-c")
+                .arg(command)
+                .env("NH_HOOK", phase.to_string())
+                .env("NH_VARIANT", &self.variant);
+            if let Some(hostname) = &self.hostname {
+                cmd.env("NH_HOSTNAME", hostname);
+            }
+            if let Some(target) = &self.target {
+                cmd.env("NH_TARGET", target);
+            }
+            if let Some(specialisation) = &self.specialisation {
+                cmd.env("NH_SPECIALISATION", specialisation);
+            }
+            debug!(?cmd);
+
+            let status = cmd
+                .status()
+                .wrap_err(format!("Running {phase} hook `{command}`"))?;
+            if !status.success() {
+                bail!("{phase} hook `{command}` failed with {status}");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Run the on_failure hooks for an error, only warning if they fail themselves
+    pub fn failed(&self, err: &color_eyre::Report) {
+        if err.is::<Rejected>() {
+            debug!("Not running the on_failure hooks, the new config was rejected");
+            return;
+        }
+        if let Err(err) = self.run(Phase::OnFailure) {
+            warn!("{err}");
+        }
+    }
+}
@@ -7,25 +7,105 @@ use color_eyre::owo_colors::OwoColorize;
 
 // Reference: https://nix.dev/manual/nix/2.18/command-ref/new-cli/nix
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Installable {
     Flake {
         reference: String,
         attribute: Vec<String>,
+        outputs: OutputsSpec,
     },
     File {
         path: PathBuf,
         attribute: Vec<String>,
+        outputs: OutputsSpec,
     },
     Store {
         path: PathBuf,
     },
+    /// A `.drv` file in the store, standing for the outputs of that derivation
+    Derivation {
+        path: PathBuf,
+        outputs: OutputsSpec,
+    },
     Expression {
         expression: String,
         attribute: Vec<String>,
+        outputs: OutputsSpec,
     },
 }
 
+/// Which outputs of a derivation to use, selected with an `^out,man` suffix
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum OutputsSpec {
+    /// No selection, so nix picks the default outputs
+    #[default]
+    Default,
+    /// `^*`
+    All,
+    Names(Vec<String>),
+}
+
+impl OutputsSpec {
+    /// Split an `^outputs` suffix from an installable, if it has a valid one
+    fn split(s: &str) -> (&str, OutputsSpec) {
+        let Some((rest, spec)) = s.rsplit_once('^') else {
+            return (s, OutputsSpec::Default);
+        };
+
+        if spec == "*" {
+            return (rest, OutputsSpec::All);
+        }
+
+        let names: Vec<String> = spec.split(',').map(str::to_string).collect();
+        let valid_name = |name: &String| {
+            !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "+-._?=".contains(c))
+        };
+        if names.iter().all(valid_name) {
+            (rest, OutputsSpec::Names(names))
+        } else {
+            (s, OutputsSpec::Default)
+        }
+    }
+}
+
+impl std::fmt::Display for OutputsSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutputsSpec::Default => Ok(()),
+            OutputsSpec::All => write!(f, "^*"),
+            OutputsSpec::Names(names) => write!(f, "^{}", names.join(",")),
+        }
+    }
+}
+
+#[test]
+fn test_outputs_spec_split() {
+    assert_eq!(
+        OutputsSpec::split("foo.bar"),
+        ("foo.bar", OutputsSpec::Default)
+    );
+    assert_eq!(
+        OutputsSpec::split(".#hello^*"),
+        (".#hello", OutputsSpec::All)
+    );
+    assert_eq!(
+        OutputsSpec::split("/nix/store/x-hello.drv^out,man"),
+        (
+            "/nix/store/x-hello.drv",
+            OutputsSpec::Names(vec![String::from("out"), String::from("man")])
+        )
+    );
+    // Not an output name, so it is left to the attribute path
+    assert_eq!(
+        OutputsSpec::split(r#"foo."a^b c""#),
+        (r#"foo."a^b c""#, OutputsSpec::Default)
+    );
+    assert_eq!(OutputsSpec::split("foo^"), ("foo^", OutputsSpec::Default));
+}
+
 impl FromArgMatches for Installable {
     fn from_arg_matches(matches: &clap::ArgMatches) -> Result<Self, clap::Error> {
         let mut matches = matches.clone();
@@ -38,61 +118,244 @@ impl FromArgMatches for Installable {
         let expr = matches.get_one::<String>("expr");
 
         if let Some(i) = installable {
-            let canonincal = fs::canonicalize(i);
-
-            if let Ok(p) = canonincal {
-                if p.starts_with("/nix/store") {
-                    return Ok(Self::Store { path: p });
-                }
+            if let Some(res) = parse_store(i)? {
+                return Ok(res);
             }
         }
 
         if let Some(f) = file {
+            let (attribute, outputs) = OutputsSpec::split(installable.map_or("", |s| s));
             return Ok(Self::File {
                 path: PathBuf::from(f),
-                attribute: parse_attribute(installable.cloned().unwrap_or_default()),
+                attribute: parse_attribute(attribute)?,
+                outputs,
             });
         }
 
         if let Some(e) = expr {
+            let (attribute, outputs) = OutputsSpec::split(installable.map_or("", |s| s));
             return Ok(Self::Expression {
                 expression: e.to_string(),
-                attribute: parse_attribute(installable.cloned().unwrap_or_default()),
+                attribute: parse_attribute(attribute)?,
+                outputs,
             });
         }
 
+        let from_env = || Self::from_env(|name| env::var(name).ok());
+
         if let Some(i) = installable {
-            let mut elems = i.splitn(2, '#');
-            let reference = elems.next().unwrap().to_owned();
-            return Ok(Self::Flake {
-                reference,
-                attribute: parse_attribute(elems.next().map(|s| s.to_string()).unwrap_or_default()),
-            });
+            let mut res = parse_flake(i)?;
+            // `#ATTRPATH` selects from the flake in $NH_FLAKE
+            if let Installable::Flake {
+                ref mut reference, ..
+            } = res
+            {
+                if reference.is_empty() {
+                    if let Some(Installable::Flake { reference: env, .. }) = from_env()? {
+                        *reference = env;
+                    }
+                }
+            }
+            return Ok(res);
         }
 
-        // env var fallacks
+        from_env()?.ok_or_else(|| clap::Error::new(ErrorKind::TooFewValues))
+    }
 
-        if let Ok(f) = env::var("NH_FLAKE") {
-            let mut elems = f.splitn(2, '#');
-            return Ok(Self::Flake {
-                reference: elems.next().unwrap().to_owned(),
-                attribute: parse_attribute(elems.next().map(|s| s.to_string()).unwrap_or_default()),
-            });
+    fn update_from_arg_matches(&mut self, matches: &clap::ArgMatches) -> Result<(), clap::Error> {
+        let installable = matches.get_one::<String>("installable");
+        let file = matches.get_one::<String>("file");
+        let expr = matches.get_one::<String>("expr");
+
+        if file.is_some() || expr.is_some() {
+            *self = Self::from_arg_matches(matches)?;
+            return Ok(());
         }
+        let Some(i) = installable else {
+            return Ok(());
+        };
 
-        if let Ok(f) = env::var("NH_FILE") {
-            return Ok(Self::File {
+        if let Some(res) = parse_store(i)? {
+            *self = res;
+            return Ok(());
+        }
+
+        match self {
+            // On its own, the argument is an attribute path into the file or expression
+            Installable::File {
+                attribute, outputs, ..
+            }
+            | Installable::Expression {
+                attribute, outputs, ..
+            } => {
+                let (i, spec) = OutputsSpec::split(i);
+                *attribute = parse_attribute(i)?;
+                *outputs = spec;
+            }
+            Installable::Flake { reference, .. } => {
+                let mut res = parse_flake(i)?;
+                if let Installable::Flake {
+                    reference: ref mut new,
+                    ..
+                } = res
+                {
+                    if new.is_empty() {
+                        new.clone_from(reference);
+                    }
+                }
+                *self = res;
+            }
+            Installable::Store { .. } | Installable::Derivation { .. } => {
+                *self = parse_flake(i)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Installable {
+    /// The installable from `$NH_FLAKE`, or else from `$NH_FILE` and `$NH_ATTR`. Empty
+    /// variables count as unset.
+    fn from_env<F>(var: F) -> Result<Option<Self>, clap::Error>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let var = |name| var(name).filter(|value| !value.is_empty());
+
+        if let Some(f) = var("NH_FLAKE") {
+            return parse_flake(&f).map(Some);
+        }
+
+        if let Some(f) = var("NH_FILE") {
+            let attribute = var("NH_ATTR").unwrap_or_default();
+            let (attribute, outputs) = OutputsSpec::split(&attribute);
+            return Ok(Some(Self::File {
                 path: PathBuf::from(f),
-                attribute: parse_attribute(env::var("NH_ATTR").unwrap_or_default()),
-            });
+                attribute: parse_attribute(attribute)?,
+                outputs,
+            }));
         }
 
-        Err(clap::Error::new(ErrorKind::TooFewValues))
+        Ok(None)
     }
+}
+
+#[test]
+fn test_installable_from_env() {
+    let env = |vars: &'static [(&'static str, &'static str)]| {
+        move |name: &str| {
+            vars.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| value.to_string())
+        }
+    };
 
-    fn update_from_arg_matches(&mut self, _matches: &clap::ArgMatches) -> Result<(), clap::Error> {
-        todo!()
+    assert_eq!(Installable::from_env(env(&[])).unwrap(), None);
+    assert_eq!(
+        Installable::from_env(env(&[("NH_FLAKE", "/cfg#a.b^out"), ("NH_FILE", "x.nix")])).unwrap(),
+        Some(Installable::Flake {
+            reference: String::from("/cfg"),
+            attribute: vec![String::from("a"), String::from("b")],
+            outputs: OutputsSpec::Names(vec![String::from("out")]),
+        })
+    );
+    assert_eq!(
+        Installable::from_env(env(&[
+            ("NH_FLAKE", ""),
+            ("NH_FILE", "x.nix"),
+            ("NH_ATTR", r#"a."b.c""#)
+        ]))
+        .unwrap(),
+        Some(Installable::File {
+            path: PathBuf::from("x.nix"),
+            attribute: vec![String::from("a"), String::from("b.c")],
+            outputs: OutputsSpec::Default,
+        })
+    );
+    assert!(Installable::from_env(env(&[("NH_FILE", "x.nix"), ("NH_ATTR", r#"a."b"#)])).is_err());
+}
+
+#[test]
+fn test_installable_update_from_arg_matches() {
+    let update = |mut installable: Installable, args: &[&str]| {
+        let matches = Installable::augment_args_for_update(clap::Command::new("nh"))
+            .try_get_matches_from(std::iter::once("nh").chain(args.iter().copied()))
+            .unwrap();
+        installable.update_from_arg_matches(&matches).unwrap();
+        installable
+    };
+    let flake = Installable::Flake {
+        reference: String::from("/cfg"),
+        attribute: vec![String::from("a")],
+        outputs: OutputsSpec::Default,
+    };
+    let file = Installable::File {
+        path: PathBuf::from("x.nix"),
+        attribute: vec![],
+        outputs: OutputsSpec::Default,
+    };
+
+    assert_eq!(update(flake.clone(), &[]), flake);
+    assert_eq!(
+        update(flake.clone(), &["#b^*"]),
+        Installable::Flake {
+            reference: String::from("/cfg"),
+            attribute: vec![String::from("b")],
+            outputs: OutputsSpec::All,
+        }
+    );
+    assert_eq!(
+        update(flake.clone(), &["github:o/r#c"]),
+        Installable::Flake {
+            reference: String::from("github:o/r"),
+            attribute: vec![String::from("c")],
+            outputs: OutputsSpec::Default,
+        }
+    );
+    assert_eq!(
+        update(file.clone(), &["b.c"]),
+        Installable::File {
+            path: PathBuf::from("x.nix"),
+            attribute: vec![String::from("b"), String::from("c")],
+            outputs: OutputsSpec::Default,
+        }
+    );
+    assert_eq!(
+        update(flake, &["-E", "{ }", "d"]),
+        Installable::Expression {
+            expression: String::from("{ }"),
+            attribute: vec![String::from("d")],
+            outputs: OutputsSpec::Default,
+        }
+    );
+}
+
+/// A path into the store, as a derivation if it is a `.drv` file
+fn parse_store(s: &str) -> Result<Option<Installable>, clap::Error> {
+    let (path, outputs) = OutputsSpec::split(s);
+
+    let Ok(p) = fs::canonicalize(path) else {
+        return Ok(None);
+    };
+    if !p.starts_with("/nix/store") {
+        return Ok(None);
+    }
+
+    // Selecting outputs only makes sense for a derivation
+    if p.extension().is_some_and(|ext| ext == "drv") {
+        return Ok(Some(Installable::Derivation { path: p, outputs }));
     }
+    if outputs != OutputsSpec::Default {
+        return Err(clap::Error::raw(
+            ErrorKind::ValueValidation,
+            format!(
+                "Can't select outputs of {}, which is not a derivation\n",
+                p.display()
+            ),
+        ));
+    }
+    Ok(Some(Installable::Store { path: p }))
 }
 
 impl Args for Installable {
@@ -122,7 +385,8 @@ impl Args for Installable {
 Nix accepts various kinds of installables:
 
 [FLAKEREF[#ATTRPATH]]
-    Flake reference with an optional attribute path.
+    Flake reference with an optional attribute path. Without FLAKEREF, the
+    attribute path is taken from the flake in NH_FLAKE.
     [env: NH_FLAKE={}]
 
 {}, {} <FILE> [ATTRPATH]
@@ -135,13 +399,19 @@ Nix accepts various kinds of installables:
 
 [PATH]
     Path or symlink to a /nix/store path
+
+[DRVPATH[^OUTPUTS]]
+    Path to a /nix/store .drv file, with the outputs to use.
+
+Flake and file installables also accept an ^OUTPUTS suffix, like ^out,man
+or ^* for all outputs.
 "#,
                     env::var("NH_FLAKE").unwrap_or_default(),
                     "-f".yellow(),
                     "--file".yellow(),
                     env::var("NH_FILE").unwrap_or_default(),
-                    env::var("NH_ATTRP").unwrap_or_default(),
-                    "-e".yellow(),
+                    env::var("NH_ATTR").unwrap_or_default(),
+                    "-E".yellow(),
                     "--expr".yellow(),
                 )),
         )
@@ -152,83 +422,118 @@ Nix accepts various kinds of installables:
     }
 }
 
-// TODO: should handle quoted attributes, like foo."bar.baz" -> ["foo", "bar.baz"]
-// maybe use chumsky?
-fn parse_attribute<S>(s: S) -> Vec<String>
+/// Parse a `FLAKEREF[#ATTRPATH][^OUTPUTS]` installable
+pub fn parse_flake(s: &str) -> Result<Installable, clap::Error> {
+    let (reference, attribute) = s.split_once('#').unwrap_or((s, ""));
+    let (attribute, outputs) = OutputsSpec::split(attribute);
+    Ok(Installable::Flake {
+        reference: reference.to_owned(),
+        attribute: parse_attribute(attribute)?,
+        outputs,
+    })
+}
+
+/// Parse an attribute path like `foo."bar.baz".qux` into its elements, the same way as
+/// nix's `parseAttrPath`: quotes group characters, including dots, and there are no escapes
+/// or whitespace trimming. An empty element is kept unless it is the last one.
+fn parse_attribute<S>(s: S) -> Result<Vec<String>, clap::Error>
 where
     S: AsRef<str>,
 {
     let s = s.as_ref();
     let mut res = Vec::new();
-
-    if s.is_empty() {
-        return res;
-    }
-
-    let mut in_quote = false;
-
     let mut elem = String::new();
-    for char in s.chars() {
-        match char {
-            '.' => {
-                if !in_quote {
-                    res.push(elem.clone());
-                    elem = String::new();
-                } else {
-                    elem.push(char);
+    let mut chars = s.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '.' => res.push(std::mem::take(&mut elem)),
+            '"' => loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some(c) => elem.push(c),
+                    None => {
+                        return Err(clap::Error::raw(
+                            ErrorKind::ValueValidation,
+                            format!("Invalid attribute path `{s}`: missing closing quote\n"),
+                        ))
+                    }
                 }
-            }
-            '"' => {
-                in_quote = !in_quote;
-            }
-            _ => elem.push(char),
+            },
+            c => elem.push(c),
         }
     }
-
-    res.push(elem);
-
-    if in_quote {
-        panic!("Failed to parse attribute: {}", s);
+    if !elem.is_empty() {
+        res.push(elem);
     }
 
-    res
+    Ok(res)
 }
 
 #[test]
 fn test_parse_attribute() {
-    assert_eq!(parse_attribute(r#"foo.bar"#), vec!["foo", "bar"]);
-    assert_eq!(parse_attribute(r#"foo."bar.baz""#), vec!["foo", "bar.baz"]);
-    let v: Vec<String> = vec![];
-    assert_eq!(parse_attribute(""), v)
+    // What nix's parseAttrPath gives for each of them
+    for (s, attribute) in [
+        ("", vec![]),
+        ("foo.bar", vec!["foo", "bar"]),
+        (r#"foo."bar.baz""#, vec!["foo", "bar.baz"]),
+        (r#"a"b.c"d"#, vec!["ab.cd"]),
+        (" foo . bar ", vec![" foo ", " bar "]),
+        (r#"foo."a\b""#, vec!["foo", r"a\b"]),
+        ("foo.${bar}", vec!["foo", "${bar}"]),
+        ("foo..bar", vec!["foo", "", "bar"]),
+        (r#""".foo"#, vec!["", "foo"]),
+        (".foo", vec!["", "foo"]),
+        ("foo.", vec!["foo"]),
+        (r#"foo."""#, vec!["foo"]),
+    ] {
+        assert_eq!(parse_attribute(s).unwrap(), attribute, "{s}");
+    }
+
+    for invalid in [r#"foo."bar"#, r#"foo\"bar"#] {
+        assert!(parse_attribute(invalid).is_err(), "{invalid}");
+    }
 }
 
 impl Installable {
-    pub fn to_args(&self) -> Vec<String> {
+    pub fn to_args(&self) -> color_eyre::Result<Vec<String>> {
         let mut res = Vec::new();
         match self {
             Installable::Flake {
                 reference,
                 attribute,
+                outputs,
             } => {
-                res.push(format!("{reference}#{}", join_attribute(attribute)));
+                res.push(format!(
+                    "{reference}#{}{outputs}",
+                    join_attribute(attribute)?
+                ));
             }
-            Installable::File { path, attribute } => {
+            Installable::File {
+                path,
+                attribute,
+                outputs,
+            } => {
                 res.push(String::from("--file"));
                 res.push(path.to_str().unwrap().to_string());
-                res.push(join_attribute(attribute));
+                res.push(format!("{}{outputs}", join_attribute(attribute)?));
             }
             Installable::Expression {
                 expression,
                 attribute,
+                outputs,
             } => {
                 res.push(String::from("--expr"));
                 res.push(expression.to_string());
-                res.push(join_attribute(attribute));
+                res.push(format!("{}{outputs}", join_attribute(attribute)?));
             }
             Installable::Store { path } => res.push(path.to_str().unwrap().to_string()),
+            Installable::Derivation { path, outputs } => {
+                res.push(format!("{}{outputs}", path.to_str().unwrap()));
+            }
         }
 
-        res
+        Ok(res)
     }
 }
 
@@ -237,60 +542,137 @@ fn test_installable_to_args() {
     assert_eq!(
         (Installable::Flake {
             reference: String::from("w"),
-            attribute: ["x", "y.z"].into_iter().map(str::to_string).collect()
+            attribute: ["x", "y.z"].into_iter().map(str::to_string).collect(),
+            outputs: OutputsSpec::Default,
         })
-        .to_args(),
+        .to_args()
+        .unwrap(),
         vec![r#"w#x."y.z""#]
     );
 
     assert_eq!(
         (Installable::File {
             path: PathBuf::from("w"),
-            attribute: ["x", "y.z"].into_iter().map(str::to_string).collect()
+            attribute: ["x", "y.z"].into_iter().map(str::to_string).collect(),
+            outputs: OutputsSpec::All,
         })
-        .to_args(),
-        vec!["--file", "w", r#"x."y.z""#]
+        .to_args()
+        .unwrap(),
+        vec!["--file", "w", r#"x."y.z"^*"#]
+    );
+
+    assert_eq!(
+        (Installable::Derivation {
+            path: PathBuf::from("/nix/store/x-hello.drv"),
+            outputs: OutputsSpec::Names(vec![String::from("out"), String::from("man")]),
+        })
+        .to_args()
+        .unwrap(),
+        vec!["/nix/store/x-hello.drv^out,man"]
     );
 }
 
-fn join_attribute<I>(attribute: I) -> String
+/// Print attribute path elements so that nix parses them back, quoting the ones with a dot
+/// or empty. Nix's syntax can't express an element with a quote, or an empty last element,
+/// so those are rejected.
+pub fn join_attribute<I>(attribute: I) -> color_eyre::Result<String>
 where
     I: IntoIterator,
     I::Item: AsRef<str>,
 {
+    let attribute: Vec<I::Item> = attribute.into_iter().collect();
+    if attribute
+        .last()
+        .is_some_and(|last| last.as_ref().is_empty())
+    {
+        color_eyre::eyre::bail!("Attribute paths can't end with an empty attribute name");
+    }
+
     let mut res = String::new();
-    let mut first = true;
-    for elem in attribute {
-        if first {
-            first = false;
-        } else {
+    for (i, elem) in attribute.iter().enumerate() {
+        if i != 0 {
             res.push('.');
         }
 
         let s = elem.as_ref();
-
-        if s.contains('.') {
-            res.push_str(&format!(r#""{}""#, s));
+        if s.contains('"') {
+            color_eyre::eyre::bail!("Attribute paths can't contain a name with a quote: {s}");
+        }
+        if s.is_empty() || s.contains('.') {
+            res.push('"');
+            res.push_str(s);
+            res.push('"');
         } else {
             res.push_str(s);
         }
     }
 
-    res
+    Ok(res)
 }
 
 #[test]
 fn test_join_attribute() {
-    assert_eq!(join_attribute(vec!["foo", "bar"]), "foo.bar");
-    assert_eq!(join_attribute(vec!["foo", "bar.baz"]), r#"foo."bar.baz""#);
+    assert_eq!(join_attribute(vec!["foo", "bar"]).unwrap(), "foo.bar");
+    assert_eq!(
+        join_attribute(vec!["foo", "bar.baz"]).unwrap(),
+        r#"foo."bar.baz""#
+    );
+    assert_eq!(
+        join_attribute(vec!["a b", "${e}", r"c\d", "", "f"]).unwrap(),
+        r#"a b.${e}.c\d."".f"#
+    );
+    assert_eq!(join_attribute(Vec::<&str>::new()).unwrap(), "");
+
+    // What nix can't parse back
+    assert!(join_attribute(vec!["foo", r#"a"b"#]).is_err());
+    assert!(join_attribute(vec![r#"a.b""#]).is_err());
+    assert!(join_attribute(vec!["foo", ""]).is_err());
+}
+
+#[cfg(test)]
+proptest::proptest! {
+    #[test]
+    // A small alphabet, so that quotes, dots and empty names are frequent
+    fn test_attribute_round_trip(attribute in proptest::collection::vec("[a .\"${}]{0,4}", 0..5)) {
+        let expressible = attribute.iter().all(|elem| !elem.contains('"'))
+            && attribute.last().is_none_or(|last| !last.is_empty());
+        match join_attribute(&attribute) {
+            Ok(printed) => {
+                proptest::prop_assert!(expressible);
+                proptest::prop_assert_eq!(parse_attribute(&printed).unwrap(), attribute);
+            }
+            Err(_) => proptest::prop_assert!(!expressible),
+        }
+    }
+
+    #[test]
+    fn test_parse_attribute_never_panics(s in ".*") {
+        if let Ok(attribute) = parse_attribute(&s) {
+            if let Ok(printed) = join_attribute(&attribute) {
+                proptest::prop_assert_eq!(parse_attribute(printed).unwrap(), attribute);
+            }
+        }
+    }
 }
 
 impl Installable {
+    /// Resolve the reference of a flake installable, see [`crate::flake_ref::resolve`]
+    pub fn resolve(mut self) -> color_eyre::Result<Self> {
+        if let Installable::Flake {
+            ref mut reference, ..
+        } = self
+        {
+            *reference = crate::flake_ref::resolve(reference)?;
+        }
+        Ok(self)
+    }
+
     pub fn str_kind(&self) -> &str {
         match self {
             Installable::Flake { .. } => "flake",
             Installable::File { .. } => "file",
             Installable::Store { .. } => "store path",
+            Installable::Derivation { .. } => "derivation",
             Installable::Expression { .. } => "expression",
         }
     }
@@ -48,6 +48,8 @@ pub enum NHCommand {
     Darwin(DarwinArgs),
     Search(SearchArgs),
     Clean(CleanProxy),
+    Config(ConfigArgs),
+    Doctor(DoctorArgs),
     #[command(hide = true)]
     Completions(CompletionArgs),
 }
@@ -58,11 +60,45 @@ impl NHCommand {
             NHCommand::Os(args) => args.run(),
             NHCommand::Search(args) => args.run(),
             NHCommand::Clean(proxy) => proxy.command.run(),
+            NHCommand::Config(args) => args.run(),
+            NHCommand::Doctor(args) => args.run(),
             NHCommand::Completions(args) => args.run(),
             NHCommand::Home(args) => args.run(),
             NHCommand::Darwin(args) => args.run(),
         }
     }
+
+    /// What the command builds or inspects, if it works on an installable
+    pub fn installable(&self) -> Option<&Installable> {
+        match self {
+            NHCommand::Os(args) => match &args.subcommand {
+                OsSubcommand::Switch(args)
+                | OsSubcommand::Boot(args)
+                | OsSubcommand::Test(args)
+                | OsSubcommand::Build(args) => Some(&args.common.installable),
+                OsSubcommand::Repl(args) => Some(&args.installable),
+                OsSubcommand::Inputs(args) => Some(&args.installable),
+                OsSubcommand::Info(_) | OsSubcommand::Stats(_) => None,
+            },
+            NHCommand::Home(args) => match &args.subcommand {
+                HomeSubcommand::Switch(args) | HomeSubcommand::Build(args) => {
+                    Some(&args.common.installable)
+                }
+                HomeSubcommand::Repl(args) => Some(&args.installable),
+            },
+            NHCommand::Darwin(args) => match &args.subcommand {
+                DarwinSubcommand::Switch(args) | DarwinSubcommand::Build(args) => {
+                    Some(&args.common.installable)
+                }
+                DarwinSubcommand::Repl(args) => Some(&args.installable),
+            },
+            NHCommand::Search(_)
+            | NHCommand::Clean(_)
+            | NHCommand::Config(_)
+            | NHCommand::Doctor(_)
+            | NHCommand::Completions(_) => None,
+        }
+    }
 }
 
 #[derive(Args, Debug)]
@@ -94,6 +130,12 @@ pub enum OsSubcommand {
 
     /// List available generations from profile path
     Info(OsGenerationsArgs),
+
+    /// Show the slowest derivations and cache misses of recent builds
+    Stats(OsStatsArgs),
+
+    /// List the inputs of the flake with their revision and age
+    Inputs(OsInputsArgs),
 }
 
 #[derive(Debug, Args)]
@@ -127,28 +169,70 @@ pub struct OsRebuildArgs {
 
 #[derive(Debug, Args)]
 pub struct CommonRebuildArgs {
-    /// Only print actions, without performing them
+    /// Only print what would be built or fetched, without building or activating anything
     #[arg(long, short = 'n')]
     pub dry: bool,
 
     /// Ask for confirmation
-    #[arg(long, short)]
+    #[arg(long, short, overrides_with = "no_ask")]
     pub ask: bool,
 
+    /// Don't ask for confirmation, even if enabled in nh.toml
+    #[arg(long, overrides_with = "ask")]
+    pub no_ask: bool,
+
     #[command(flatten)]
     pub installable: Installable,
 
-    /// Run git pull on the flake before building specified configuration
-    #[arg(long, short = 'p')]
-    pub pull: bool,
+    #[command(flatten)]
+    pub pull: PullArgs,
 
-    /// Don't use nix-output-monitor for the build process
-    #[arg(long)]
+    /// Refuse to build from a local flake with uncommitted changes, untracked files or unpushed commits
+    #[arg(long, env = "NH_REQUIRE_CLEAN", overrides_with = "no_require_clean")]
+    pub require_clean: bool,
+
+    /// Allow building from a dirty flake, even if --require-clean is enabled in nh.toml
+    #[arg(long, overrides_with = "require_clean")]
+    pub no_require_clean: bool,
+
+    /// Don't render build progress, show the raw output of nix instead
+    #[arg(long, overrides_with = "nom")]
     pub no_nom: bool,
 
+    /// Render build progress, even if no_nom is enabled in nh.toml
+    #[arg(long, overrides_with = "no_nom")]
+    pub nom: bool,
+
     /// Path to save the result link, defaults to using a temporary directory
     #[arg(long, short)]
     pub out_link: Option<PathBuf>,
+
+    /// Send a notification when confirmation is needed or the rebuild finishes
+    #[arg(long, env = "NH_NOTIFY", overrides_with = "no_notify")]
+    pub notify: bool,
+
+    /// Don't send notifications, even if enabled in nh.toml
+    #[arg(long, overrides_with = "notify")]
+    pub no_notify: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct PullArgs {
+    /// Run git pull on the flake before building specified configuration
+    #[arg(long, short = 'p')]
+    pub pull: bool,
+
+    /// Rebase local commits on top of the upstream branch when pulling
+    #[arg(long, requires = "pull", conflicts_with = "ff_only")]
+    pub rebase: bool,
+
+    /// Only pull if the branch can be fast-forwarded
+    #[arg(long, requires = "pull")]
+    pub ff_only: bool,
+
+    /// Stash uncommitted changes before pulling and restore them afterwards
+    #[arg(long, requires = "pull")]
+    pub autostash: bool,
 }
 
 #[derive(Debug, Args)]
@@ -168,8 +252,29 @@ pub struct OsGenerationsArgs {
     pub profile: Option<String>,
 }
 
+#[derive(Debug, Args)]
+pub struct OsInputsArgs {
+    #[command(flatten)]
+    pub installable: Installable,
+}
+
+#[derive(Debug, Args)]
+pub struct OsStatsArgs {
+    /// Show the builds of this hostname, defaults to the current one
+    #[arg(long, short = 'H')]
+    pub hostname: Option<String>,
+
+    /// Number of recent builds to include
+    #[arg(long, short = 'n', default_value = "10")]
+    pub last: usize,
+
+    /// Number of slowest derivations to display
+    #[arg(long, short, default_value = "10")]
+    pub top: usize,
+}
+
 #[derive(Args, Debug)]
-/// Searches packages by querying search.nixos.org
+/// Searches packages or options by querying search.nixos.org
 pub struct SearchArgs {
     #[arg(long, short, default_value = "30")]
     /// Number of search results to display
@@ -184,13 +289,52 @@ pub struct SearchArgs {
     /// Name of the channel to query (e.g nixos-23.11, nixos-unstable, etc)
     pub channel: String,
 
-    /// Name of the package to search
+    /// Whether --channel was given on the command line, rather than by the environment
+    /// or the configuration
+    #[arg(skip)]
+    pub channel_given: bool,
+
+    #[arg(long, value_enum)]
+    /// Search offline, in a nixpkgs evaluated locally instead of search.nixos.org
+    pub nixpkgs_from: Option<SearchNixpkgsFrom>,
+
+    #[arg(long, env = "NH_FLAKE")]
+    /// Flake whose nixpkgs input is searched with `--nixpkgs-from flake` or `--pinned`
+    pub flake: Option<String>,
+
+    #[arg(long)]
+    /// Search NixOS options instead of packages
+    ///
+    /// Offline, the options are those of the NixOS configuration of this machine, built
+    /// from the flake or from <nixpkgs/nixos> depending on --nixpkgs-from. A flake's
+    /// `#ATTRPATH` selects another configuration, like `#homeConfigurations.NAME` for the
+    /// options of a Home Manager configuration
+    pub options: bool,
+
+    #[arg(long, requires = "options", value_name = "FILE")]
+    /// Search the options of an options JSON file, like the one of Home Manager's manual
+    pub options_json: Option<PathBuf>,
+
+    #[arg(long, conflicts_with_all = ["nixpkgs_from", "options"])]
+    /// Compare the results with the nixpkgs locked by the flake
+    ///
+    /// The channel closest to the branch the flake's nixpkgs input tracks is searched
+    /// instead of --channel, and packages whose pinned version is older are flagged
+    pub pinned: bool,
+
+    #[arg(long)]
+    /// Print the results as a JSON array, most relevant first, without any other text on stdout
+    pub json: bool,
+
+    /// Name of the package or option to search
     pub query: String,
 }
 
 #[derive(Debug, Clone, ValueEnum)]
 pub enum SearchNixpkgsFrom {
+    /// The nixpkgs input of a flake
     Flake,
+    /// `<nixpkgs>` from the NIX_PATH
     Path,
 }
 
@@ -231,16 +375,28 @@ pub struct CleanArgs {
     pub dry: bool,
 
     /// Ask for confimation
-    #[arg(long, short)]
+    #[arg(long, short, overrides_with = "no_ask")]
     pub ask: bool,
 
+    /// Don't ask for confirmation, even if enabled in nh.toml
+    #[arg(long, overrides_with = "ask")]
+    pub no_ask: bool,
+
     /// Don't run nix store --gc
-    #[arg(long)]
+    #[arg(long, overrides_with = "gc")]
     pub nogc: bool,
 
+    /// Run nix store --gc, even if nogc is enabled in nh.toml
+    #[arg(long, overrides_with = "nogc")]
+    pub gc: bool,
+
     /// Don't clean gcroots
-    #[arg(long)]
+    #[arg(long, overrides_with = "gcroots")]
     pub nogcroots: bool,
+
+    /// Clean gcroots, even if nogcroots is enabled in nh.toml
+    #[arg(long, overrides_with = "nogcroots")]
+    pub gcroots: bool,
 }
 
 #[derive(Debug, Clone, Args)]
@@ -310,6 +466,27 @@ pub struct HomeReplArgs {
     pub extra_args: Vec<String>,
 }
 
+#[derive(Debug, Args)]
+/// Inspect the configuration read from nh.toml
+pub struct ConfigArgs {
+    #[command(subcommand)]
+    pub subcommand: ConfigSubcommand,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ConfigSubcommand {
+    /// Print the effective configuration, after merging all files and host overrides
+    Show,
+}
+
+#[derive(Debug, Args)]
+/// Diagnose the environment nh runs in
+pub struct DoctorArgs {
+    /// Output the checks as JSON
+    #[arg(long)]
+    pub json: bool,
+}
+
 #[derive(Debug, Parser)]
 /// Generate shell completion files into stdout
 pub struct CompletionArgs {
@@ -369,7 +546,33 @@ pub struct UpdateArgs {
     /// Update all flake inputs
     pub update: bool,
 
-    #[arg(short = 'U', long = "update-input")]
-    /// Update a single flake input
-    pub update_input: Option<String>,
+    #[arg(short = 'U', long = "update-input", value_name = "INPUT")]
+    /// Update only this flake input, or channel for file and expression installables. Can be
+    /// repeated and accepts globs like 'nixpkgs*'
+    pub update_input: Vec<String>,
+
+    /// Also write the summary of the updated inputs as JSON to this file, or to stdout with `-`
+    #[arg(long, value_name = "FILE")]
+    pub update_json: Option<PathBuf>,
+
+    /// Commit the updated flake.lock once the configuration was built, or only once it was activated
+    ///
+    /// If the rebuild fails before that, flake.lock is reverted
+    #[arg(
+        long,
+        value_enum,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "build"
+    )]
+    pub commit_lock: Option<CommitLock>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CommitLock {
+    /// After a successful build
+    Build,
+    /// After a successful switch, boot or test
+    Switch,
 }
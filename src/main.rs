@@ -1,21 +1,34 @@
 mod clean;
 mod commands;
 mod completion;
+mod config;
 mod darwin;
+mod doctor;
+mod flake_lock;
+mod flake_ref;
 mod generations;
+mod git;
+mod history;
 mod home;
+mod hooks;
 mod installable;
 mod interface;
 mod json;
 mod logging;
+mod nix_info;
 mod nixos;
+mod notify;
+mod progress;
 mod search;
+mod search_index;
 mod update;
 mod util;
 
 use color_eyre::Result;
 use tracing::debug;
 
+use crate::interface::NHCommand;
+
 const NH_VERSION: &str = env!("CARGO_PKG_VERSION");
 const NH_REV: Option<&str> = option_env!("NH_REV");
 
@@ -28,8 +41,30 @@ fn main() -> Result<()> {
         }
     }
 
-    let args = <crate::interface::Main as clap::Parser>::parse();
+    // Parse without the configuration first, so that a broken nh.toml doesn't get in the
+    // way of --help or the commands diagnosing it
+    let command = <crate::interface::Main as clap::CommandFactory>::command;
+    let args = <crate::interface::Main as clap::FromArgMatches>::from_arg_matches(
+        &command().get_matches(),
+    )
+    .unwrap_or_else(|e| e.exit());
     crate::logging::setup_logging(args.verbose)?;
+
+    let fallback = matches!(
+        args.command,
+        NHCommand::Config(_) | NHCommand::Doctor(_) | NHCommand::Completions(_)
+    );
+    // The flake-local layer comes from the installable of the command line
+    let config = crate::config::init(fallback, args.command.installable())?;
+    let matches = config.apply_defaults(command()).get_matches();
+    let mut args = <crate::interface::Main as clap::FromArgMatches>::from_arg_matches(&matches)
+        .unwrap_or_else(|e| e.exit());
+    if let NHCommand::Search(search) = &mut args.command {
+        search.channel_given = matches
+            .subcommand_matches("search")
+            .and_then(|m| m.value_source("channel"))
+            == Some(clap::parser::ValueSource::CommandLine);
+    }
     tracing::debug!("{args:#?}");
     tracing::debug!(%NH_VERSION, ?NH_REV);
 
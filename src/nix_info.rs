@@ -0,0 +1,239 @@
+use std::fmt;
+use std::process::Command;
+
+use color_eyre::eyre::{bail, eyre, Context};
+use once_cell::sync::OnceCell;
+use regex::Regex;
+use semver::Version;
+use tracing::debug;
+
+use crate::Result;
+
+static NIX_INFO: OnceCell<NixInfo> = OnceCell::new();
+
+/// Oldest CppNix with flakes and the new CLI. It also has everything else nh relies on
+/// without checking the version: `nix store gc` and `nix build --log-format internal-json`
+/// with the activity types the progress renderer reads. So they only need
+/// [`supported`] to be checked before they are run.
+const MIN_NIX: Version = Version::new(2, 4, 0);
+/// First Lix release, forked from CppNix 2.18
+const MIN_LIX: Version = Version::new(2, 90, 0);
+/// CppNix version that added `nix flake update <input>` and `--flake`
+const FLAKE_UPDATE_INPUTS: Version = Version::new(2, 19, 0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Implementation {
+    Nix,
+    Lix,
+    Determinate,
+}
+
+impl fmt::Display for Implementation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Implementation::Nix => "Nix",
+            Implementation::Lix => "Lix",
+            Implementation::Determinate => "Determinate Nix",
+        })
+    }
+}
+
+/// The Nix implementation in `$PATH`, as reported by `nix --version`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NixInfo {
+    pub implementation: Implementation,
+    /// Version of the CLI, which Determinate Nix keeps in line with the CppNix it is based on
+    pub version: Version,
+    /// Determinate Nix's own release, like 3.6.2
+    pub release: Option<Version>,
+}
+
+impl fmt::Display for NixInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.release {
+            Some(release) => write!(f, "{} {} ({})", self.implementation, release, self.version),
+            None => write!(f, "{} {}", self.implementation, self.version),
+        }
+    }
+}
+
+/// Detect the Nix implementation, only once
+pub fn get() -> Result<&'static NixInfo> {
+    NIX_INFO.get_or_try_init(|| {
+        let output = Command::new("nix")
+            .arg("--version")
+            .output()
+            .wrap_err("Running nix --version, is nix in PATH?")?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let info = NixInfo::parse(&stdout)?;
+        debug!(%info, "Detected nix");
+        Ok(info)
+    })
+}
+
+/// Detect the Nix implementation and fail if nh doesn't support it
+pub fn supported() -> Result<&'static NixInfo> {
+    let info = get()?;
+    info.ensure_supported()?;
+    Ok(info)
+}
+
+impl NixInfo {
+    /// Parse the first line of `nix --version`, for example:
+    ///
+    /// - `nix (Nix) 2.24.9`
+    /// - `nix (Lix, like Nix) 2.91.1`
+    /// - `nix (Determinate Nix 3.6.2) 2.29.0`
+    pub fn parse(output: &str) -> Result<Self> {
+        let line = output
+            .lines()
+            .next()
+            .ok_or_else(|| eyre!("nix --version printed nothing"))?;
+
+        let re = Regex::new(r"^nix \((?<name>[^)]*)\) (?<version>\d+\.\d+(?:\.\d+)?)").unwrap();
+        let captures = re
+            .captures(line)
+            .ok_or_else(|| eyre!("Unrecognized nix version: {line}"))?;
+
+        let name = &captures["name"];
+        let version = parse_version(&captures["version"])?;
+
+        let (implementation, release) = if name.starts_with("Lix") {
+            (Implementation::Lix, None)
+        } else if let Some(release) = name.strip_prefix("Determinate Nix ") {
+            (Implementation::Determinate, Some(parse_version(release)?))
+        } else {
+            (Implementation::Nix, None)
+        };
+
+        Ok(Self {
+            implementation,
+            version,
+            release,
+        })
+    }
+
+    pub fn ensure_supported(&self) -> Result<()> {
+        let minimum = match self.implementation {
+            Implementation::Nix | Implementation::Determinate => &MIN_NIX,
+            Implementation::Lix => &MIN_LIX,
+        };
+
+        if self.version < *minimum {
+            bail!(
+                "{self} is not supported, nh needs {} {minimum} or newer",
+                self.implementation
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Whether `nix flake update` takes the inputs to update, instead of `nix flake lock --update-input`
+    pub fn flake_update_inputs(&self) -> bool {
+        match self.implementation {
+            Implementation::Nix | Implementation::Determinate => {
+                self.version >= FLAKE_UPDATE_INPUTS
+            }
+            // Lix was forked from 2.18, and `nix flake lock --update-input` works in all of
+            // its releases, unlike the new interface
+            Implementation::Lix => false,
+        }
+    }
+
+    /// Arguments to update the lock file of a flake, either all of its inputs or only some
+    pub fn flake_update_args(&self, reference: &str, inputs: &[String]) -> Vec<String> {
+        let mut args = vec![String::from("flake")];
+
+        if self.flake_update_inputs() {
+            args.push(String::from("update"));
+            args.extend(inputs.iter().cloned());
+            args.push(String::from("--flake"));
+            args.push(reference.to_owned());
+        } else if inputs.is_empty() {
+            args.push(String::from("update"));
+            args.push(reference.to_owned());
+        } else {
+            args.push(String::from("lock"));
+            args.push(reference.to_owned());
+            for input in inputs {
+                args.push(String::from("--update-input"));
+                args.push(input.clone());
+            }
+        }
+
+        args
+    }
+}
+
+/// Parse a version like `2.25` or `2.25.0`
+fn parse_version(s: &str) -> Result<Version> {
+    let padded;
+    let s = if s.matches('.').count() == 1 {
+        padded = format!("{s}.0");
+        &padded
+    } else {
+        s
+    };
+
+    Version::parse(s).wrap_err(format!("Parsing version {s}"))
+}
+
+#[test]
+fn test_nix_info_parse() {
+    let nix = NixInfo::parse("nix (Nix) 2.24.9\n").unwrap();
+    assert_eq!(nix.implementation, Implementation::Nix);
+    assert_eq!(nix.version, Version::new(2, 24, 9));
+    assert!(nix.flake_update_inputs());
+
+    let lix = NixInfo::parse("nix (Lix, like Nix) 2.91.1\nSystem type: x86_64-linux\n").unwrap();
+    assert_eq!(lix.implementation, Implementation::Lix);
+    assert_eq!(lix.version, Version::new(2, 91, 1));
+    assert!(!lix.flake_update_inputs());
+
+    let determinate = NixInfo::parse("nix (Determinate Nix 3.6.2) 2.29.0").unwrap();
+    assert_eq!(determinate.implementation, Implementation::Determinate);
+    assert_eq!(determinate.version, Version::new(2, 29, 0));
+    assert_eq!(determinate.release, Some(Version::new(3, 6, 2)));
+
+    let pre = NixInfo::parse("nix (Nix) 2.26.0pre20241122_dirty").unwrap();
+    assert_eq!(pre.version, Version::new(2, 26, 0));
+
+    assert!(NixInfo::parse("nix (Nix) 2.3.16")
+        .unwrap()
+        .ensure_supported()
+        .is_err());
+    assert!(NixInfo::parse("bash: nix: not found").is_err());
+}
+
+#[test]
+fn test_flake_update_args() {
+    let inputs = vec![String::from("nixpkgs")];
+
+    let new = NixInfo::parse("nix (Nix) 2.19.0").unwrap();
+    assert_eq!(
+        new.flake_update_args(".", &inputs),
+        ["flake", "update", "nixpkgs", "--flake", "."]
+    );
+
+    let old = NixInfo::parse("nix (Nix) 2.18.1").unwrap();
+    assert_eq!(old.flake_update_args(".", &[]), ["flake", "update", "."]);
+    assert_eq!(
+        old.flake_update_args(".", &inputs),
+        ["flake", "lock", ".", "--update-input", "nixpkgs"]
+    );
+
+    // Lix versions are above 2.19 but take the arguments of 2.18
+    let lix = NixInfo::parse("nix (Lix, like Nix) 2.93.0").unwrap();
+    assert_eq!(lix.flake_update_args(".", &[]), ["flake", "update", "."]);
+    assert_eq!(
+        lix.flake_update_args(".", &inputs),
+        ["flake", "lock", ".", "--update-input", "nixpkgs"]
+    );
+
+    let determinate = NixInfo::parse("nix (Determinate Nix 3.6.2) 2.29.0").unwrap();
+    assert_eq!(
+        determinate.flake_update_args(".", &inputs),
+        ["flake", "update", "nixpkgs", "--flake", "."]
+    );
+}
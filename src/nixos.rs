@@ -7,16 +7,26 @@ use tracing::{debug, info, warn};
 
 use crate::commands;
 use crate::commands::Command;
+use crate::config;
+use crate::flake_lock;
+use crate::flake_ref;
 use crate::generations;
+use crate::git;
+use crate::history;
+use crate::hooks::{HookRunner, Phase, Rejected};
 use crate::installable::Installable;
 use crate::interface::OsSubcommand::{self};
-use crate::interface::{self, OsGenerationsArgs, OsRebuildArgs, OsReplArgs};
-use crate::update::{pull, update};
+use crate::interface::{
+    self, CommitLock, OsGenerationsArgs, OsInputsArgs, OsRebuildArgs, OsReplArgs, OsStatsArgs,
+};
+use crate::nix_info;
+use crate::notify::Notifier;
+use crate::update::{pull, update, LockUpdate};
 
 const SYSTEM_PROFILE: &str = "/nix/var/nix/profiles/system";
 const CURRENT_PROFILE: &str = "/run/current-system";
 
-const SPEC_LOCATION: &str = "/etc/specialisation";
+pub const SPEC_LOCATION: &str = "/etc/specialisation";
 
 impl interface::OsArgs {
     pub fn run(self) -> Result<()> {
@@ -26,13 +36,15 @@ impl interface::OsArgs {
             OsSubcommand::Test(args) => args.rebuild(Test),
             OsSubcommand::Switch(args) => args.rebuild(Switch),
             OsSubcommand::Build(args) => {
-                if args.common.ask || args.common.dry {
-                    warn!("`--ask` and `--dry` have no effect for `nh os build`");
+                if args.common.ask {
+                    warn!("`--ask` has no effect for `nh os build`");
                 }
                 args.rebuild(Build)
             }
             OsSubcommand::Repl(args) => args.run(),
             OsSubcommand::Info(args) => args.info(),
+            OsSubcommand::Stats(args) => args.stats(),
+            OsSubcommand::Inputs(args) => args.inputs(),
         }
     }
 }
@@ -46,7 +58,36 @@ enum OsRebuildVariant {
 }
 
 impl OsRebuildArgs {
-    fn rebuild(self, variant: OsRebuildVariant) -> Result<()> {
+    fn rebuild(mut self, variant: OsRebuildVariant) -> Result<()> {
+        nix_info::supported()?;
+        let config = config::get()?;
+        self.extra_args
+            .splice(0..0, config.rebuild.extra_args.iter().cloned());
+
+        let variant_name = format!("{variant:?}").to_lowercase();
+        let notifier = Notifier::new(self.common.notify, format!("nh os {variant_name}"));
+        let mut hooks = HookRunner::new(&config.hooks.os, &variant_name);
+
+        let mut lock = None;
+        let res = self.rebuild_steps(variant, config, &notifier, &mut hooks, &mut lock);
+        if let Some(lock) = &lock {
+            lock.finished(res.is_ok());
+        }
+        if let Err(err) = &res {
+            hooks.failed(err);
+        }
+        notifier.finished(&res);
+        res
+    }
+
+    fn rebuild_steps(
+        mut self,
+        variant: OsRebuildVariant,
+        config: &config::Config,
+        notifier: &Notifier,
+        hooks: &mut HookRunner,
+        lock: &mut Option<LockUpdate>,
+    ) -> Result<()> {
         use OsRebuildVariant::*;
 
         let elevate = if self.bypass_root_check {
@@ -59,18 +100,38 @@ impl OsRebuildArgs {
             true
         };
 
-        if self.common.pull {
+        self.common.installable = self.common.installable.resolve()?;
+
+        if self.common.pull.pull {
             pull(
                 &self.common.installable,
-                self.update_args.update,
+                &self.common.pull,
+                self.update_args.enabled(),
                 self.common.dry,
             )?;
         }
 
-        if self.update_args.update {
-            update(&self.common.installable, self.update_args.update_input)?;
+        if self.update_args.enabled() {
+            *lock = update(
+                &self.common.installable,
+                &self.update_args,
+                elevate,
+                self.common.dry,
+            )?;
         }
 
+        git::check_clean(
+            &self.common.installable,
+            self.common.require_clean,
+            self.update_args.enabled(),
+        )?;
+        let rebuild_config = &config.rebuild;
+        flake_lock::warn_stale(
+            &self.common.installable,
+            &rebuild_config.stale_inputs(),
+            rebuild_config.stale_after()?,
+        )?;
+
         let hostname = match &self.hostname {
             Some(h) => h.to_owned(),
             None => hostname::get()
@@ -80,6 +141,28 @@ impl OsRebuildArgs {
                 .to_owned(),
         };
 
+        let hostname = flake_ref::select_configuration(
+            &self.common.installable,
+            "nixosConfigurations",
+            &hostname,
+            &self.extra_args,
+        )?;
+        hooks.hostname(&hostname);
+
+        let toplevel = toplevel_for(&hostname, self.common.installable.clone());
+
+        if self.common.dry {
+            if self.common.ask {
+                warn!("--ask has no effect as dry run was requested");
+            }
+            commands::Build::new(toplevel)
+                .extra_args(&self.extra_args)
+                .message("Evaluating NixOS configuration (dry run)")
+                .dry_run()?
+                .print();
+            return Ok(());
+        }
+
         let out_path: Box<dyn crate::util::MaybeTempPath> = match self.common.out_link {
             Some(ref p) => Box::new(p.clone()),
             None => Box::new({
@@ -90,16 +173,21 @@ impl OsRebuildArgs {
 
         debug!(?out_path);
 
-        let toplevel = toplevel_for(hostname, self.common.installable.clone());
+        hooks.run(Phase::PreBuild)?;
 
         commands::Build::new(toplevel)
             .extra_arg("--out-link")
             .extra_arg(out_path.get_path())
             .extra_args(&self.extra_args)
             .message("Building NixOS configuration")
-            .nom(!self.common.no_nom)
+            .progress(!self.common.no_nom)
+            .history(&hostname)
             .run()?;
 
+        if let Some(lock) = lock.as_mut() {
+            lock.reached(CommitLock::Build)?;
+        }
+
         let current_specialisation = std::fs::read_to_string(SPEC_LOCATION).ok();
 
         let target_specialisation = if self.no_specialisation {
@@ -117,6 +205,12 @@ impl OsRebuildArgs {
 
         target_profile.try_exists().context("Doesn't exist")?;
 
+        hooks.target(out_path.get_path());
+        if let Some(spec) = &target_specialisation {
+            hooks.specialisation(spec);
+        }
+        hooks.run(Phase::PostBuild)?;
+
         Command::new("nvd")
             .arg("diff")
             .arg(CURRENT_PROFILE)
@@ -124,22 +218,22 @@ impl OsRebuildArgs {
             .message("Comparing changes")
             .run()?;
 
-        if self.common.dry || matches!(variant, Build) {
-            if self.common.ask {
-                warn!("--ask has no effect as dry run was requested");
-            }
+        if let Build = variant {
             return Ok(());
         }
 
         if self.common.ask {
             info!("Apply the config?");
+            notifier.confirmation();
             let confirmation = dialoguer::Confirm::new().default(false).interact()?;
 
             if !confirmation {
-                bail!("User rejected the new config");
+                bail!(Rejected);
             }
         }
 
+        hooks.run(Phase::PreActivate)?;
+
         if let Test | Switch = variant {
             // !! Use the target profile aka spec-namespaced
             let switch_to_configuration =
@@ -173,6 +267,12 @@ impl OsRebuildArgs {
                 .run()?;
         }
 
+        hooks.run(Phase::PostActivate)?;
+
+        if let Some(lock) = lock.as_mut() {
+            lock.reached(CommitLock::Switch)?;
+        }
+
         // Make sure out_path is not accidentally dropped
         // https://docs.rs/tempfile/3.12.0/tempfile/index.html#early-drop-pitfall
         drop(out_path);
@@ -210,7 +310,7 @@ pub fn toplevel_for<S: AsRef<str>>(hostname: S, installable: Installable) -> Ins
         } => {
             attribute.extend(toplevel);
         }
-        Installable::Store { .. } => {}
+        Installable::Store { .. } | Installable::Derivation { .. } => {}
     }
 
     res
@@ -218,7 +318,7 @@ pub fn toplevel_for<S: AsRef<str>>(hostname: S, installable: Installable) -> Ins
 
 impl OsReplArgs {
     fn run(self) -> Result<()> {
-        let mut target_installable = self.installable;
+        let mut target_installable = self.installable.resolve()?;
 
         if matches!(target_installable, Installable::Store { .. }) {
             bail!("Nix doesn't support nix store installables.");
@@ -227,6 +327,12 @@ impl OsReplArgs {
         let hostname = self
             .hostname
             .unwrap_or_else(|| hostname::get().unwrap().to_str().unwrap().to_string());
+        let hostname = flake_ref::select_configuration(
+            &target_installable,
+            "nixosConfigurations",
+            &hostname,
+            std::iter::empty::<&str>(),
+        )?;
 
         if let Installable::Flake {
             ref mut attribute, ..
@@ -240,7 +346,7 @@ impl OsReplArgs {
 
         Command::new("nix")
             .arg("repl")
-            .args(target_installable.to_args())
+            .args(target_installable.to_args()?)
             .run()?;
 
         Ok(())
@@ -290,3 +396,36 @@ impl OsGenerationsArgs {
         Ok(())
     }
 }
+
+impl OsStatsArgs {
+    fn stats(&self) -> Result<()> {
+        let hostname = match &self.hostname {
+            Some(h) => h.to_owned(),
+            None => hostname::get()
+                .context("Failed to get hostname")?
+                .to_string_lossy()
+                .into_owned(),
+        };
+
+        let records = history::load(&hostname, self.last)?;
+        history::print_report(&hostname, &records, self.top);
+
+        Ok(())
+    }
+}
+
+impl OsInputsArgs {
+    fn inputs(&self) -> Result<()> {
+        let Installable::Flake { reference, .. } = &self.installable else {
+            bail!(
+                "Only flake installables have inputs, {} is not supported",
+                self.installable.str_kind()
+            );
+        };
+
+        let lock = flake_lock::for_reference(&flake_ref::resolve(reference)?)?;
+        flake_lock::print_inputs(&lock, chrono::Utc::now().timestamp());
+
+        Ok(())
+    }
+}
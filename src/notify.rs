@@ -0,0 +1,162 @@
+use std::io::{IsTerminal, Write};
+use std::process::{Command, Stdio};
+use std::time::Instant;
+
+use color_eyre::Result;
+use tracing::debug;
+
+use crate::progress::format_duration;
+use crate::util;
+
+#[derive(Debug, Clone, Copy)]
+enum Urgency {
+    Normal,
+    Critical,
+}
+
+impl Urgency {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Urgency::Normal => "normal",
+            Urgency::Critical => "critical",
+        }
+    }
+
+    /// Urgency byte of the freedesktop notification spec
+    fn as_byte(&self) -> u8 {
+        match self {
+            Urgency::Normal => 1,
+            Urgency::Critical => 2,
+        }
+    }
+}
+
+/// Notifies the user about the progress of a long running rebuild, when enabled
+#[derive(Debug)]
+pub struct Notifier {
+    enabled: bool,
+    title: String,
+    started: Instant,
+}
+
+impl Notifier {
+    pub fn new<S: AsRef<str>>(enabled: bool, title: S) -> Self {
+        Self {
+            enabled,
+            title: title.as_ref().to_owned(),
+            started: Instant::now(),
+        }
+    }
+
+    /// The rebuild is waiting for the user to confirm
+    pub fn confirmation(&self) {
+        self.send(
+            Urgency::Normal,
+            &format!(
+                "Waiting for confirmation after {}",
+                format_duration(self.started.elapsed())
+            ),
+        );
+    }
+
+    /// The rebuild is over, notify about the outcome
+    pub fn finished(&self, result: &Result<()>) {
+        let elapsed = format_duration(self.started.elapsed());
+        match result {
+            Ok(()) => self.send(Urgency::Normal, &format!("Finished in {elapsed}")),
+            Err(err) => self.send(Urgency::Critical, &format!("Failed after {elapsed}: {err}")),
+        }
+    }
+
+    fn send(&self, urgency: Urgency, body: &str) {
+        if !self.enabled {
+            return;
+        }
+
+        debug!(title = self.title, body, "Sending notification");
+
+        if notify_send(&self.title, body, urgency) || gdbus(&self.title, body, urgency) {
+            return;
+        }
+
+        terminal(&self.title, body);
+    }
+}
+
+fn notify_send(summary: &str, body: &str, urgency: Urgency) -> bool {
+    if util::find_in_path("notify-send").is_none() {
+        return false;
+    }
+
+    Command::new("notify-send")
+        .args([
+            "--app-name",
+            "nh",
+            "--urgency",
+            urgency.as_str(),
+            summary,
+            body,
+        ])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|s| s.success())
+}
+
+/// Call org.freedesktop.Notifications directly, for systems without libnotify's CLI
+fn gdbus(summary: &str, body: &str, urgency: Urgency) -> bool {
+    if util::find_in_path("gdbus").is_none() {
+        return false;
+    }
+
+    Command::new("gdbus")
+        .args([
+            "call",
+            "--session",
+            "--dest",
+            "org.freedesktop.Notifications",
+            "--object-path",
+            "/org/freedesktop/Notifications",
+            "--method",
+            "org.freedesktop.Notifications.Notify",
+            "'nh'",
+            "0",
+            "''",
+            &gvariant_string(summary),
+            &gvariant_string(body),
+            "[]",
+            &format!("{{'urgency': <byte {}>}}", urgency.as_byte()),
+            "-1",
+        ])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|s| s.success())
+}
+
+fn gvariant_string(s: &str) -> String {
+    format!("'{}'", s.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+/// Fall back to an OSC 777 notification, which many terminals show natively, and a bell
+fn terminal(summary: &str, body: &str) {
+    let mut stderr = std::io::stderr();
+    if !stderr.is_terminal() {
+        return;
+    }
+
+    let sanitize = |s: &str| s.replace(['\x07', '\x1b', ';', '\n'], " ");
+    let _ = write!(
+        stderr,
+        "\x1b]777;notify;{};{}\x07\x07",
+        sanitize(summary),
+        sanitize(body)
+    );
+    let _ = stderr.flush();
+}
+
+#[test]
+fn test_gvariant_string() {
+    assert_eq!(gvariant_string("nh os switch"), "'nh os switch'");
+    assert_eq!(gvariant_string(r"it's a \ path"), r"'it\'s a \\ path'");
+}
@@ -0,0 +1,482 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{IsTerminal, Write};
+use std::time::{Duration, Instant};
+
+use owo_colors::OwoColorize;
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::Value;
+
+/// Prefix nix puts in front of every line when using `--log-format internal-json`
+const JSON_PREFIX: &str = "@nix ";
+
+/// Number of log lines kept per derivation, shown when its build fails
+const LOG_TAIL: usize = 20;
+
+/// Minimum time between two redraws of the status line
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+// Reference: https://github.com/NixOS/nix/blob/master/src/libutil/logging.hh
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ActivityKind {
+    CopyPath,
+    FileTransfer,
+    Build,
+    Substitute,
+    Other,
+}
+
+impl From<u64> for ActivityKind {
+    fn from(value: u64) -> Self {
+        match value {
+            100 => Self::CopyPath,
+            101 => Self::FileTransfer,
+            105 => Self::Build,
+            108 => Self::Substitute,
+            _ => Self::Other,
+        }
+    }
+}
+
+const RESULT_BUILD_LOG_LINE: u64 = 101;
+const RESULT_SET_PHASE: u64 = 104;
+
+const LEVEL_ERROR: u64 = 0;
+const LEVEL_WARN: u64 = 1;
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(tag = "action", rename_all = "camelCase")]
+pub enum LogEvent {
+    Start {
+        id: u64,
+        #[serde(rename = "type")]
+        kind: u64,
+        #[serde(default)]
+        fields: Vec<Value>,
+    },
+    Stop {
+        id: u64,
+    },
+    Result {
+        id: u64,
+        #[serde(rename = "type")]
+        kind: u64,
+        #[serde(default)]
+        fields: Vec<Value>,
+    },
+    Msg {
+        level: u64,
+        msg: String,
+    },
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum LogLine {
+    Event(LogEvent),
+    /// Anything that isn't part of the internal-json stream, printed as-is
+    Raw(String),
+}
+
+impl LogLine {
+    pub fn parse(line: &str) -> Self {
+        line.strip_prefix(JSON_PREFIX)
+            .and_then(|json| serde_json::from_str(json).ok())
+            .map(Self::Event)
+            .unwrap_or_else(|| Self::Raw(line.to_owned()))
+    }
+}
+
+#[test]
+fn test_log_line_parse() {
+    assert_eq!(
+        LogLine::parse(
+            r#"@nix {"action":"start","id":12,"level":3,"parent":0,"text":"building '/nix/store/abc-hello.drv'","type":105,"fields":["/nix/store/abc-hello.drv","",1,1]}"#
+        ),
+        LogLine::Event(LogEvent::Start {
+            id: 12,
+            kind: 105,
+            fields: vec![
+                "/nix/store/abc-hello.drv".into(),
+                "".into(),
+                1.into(),
+                1.into()
+            ],
+        })
+    );
+    assert_eq!(
+        LogLine::parse(r#"@nix {"action":"stop","id":12}"#),
+        LogLine::Event(LogEvent::Stop { id: 12 })
+    );
+    assert_eq!(
+        LogLine::parse(r#"@nix {"action":"somethingNew","id":12}"#),
+        LogLine::Event(LogEvent::Unknown)
+    );
+    assert_eq!(
+        LogLine::parse("warning: Git tree is dirty"),
+        LogLine::Raw(String::from("warning: Git tree is dirty"))
+    );
+}
+
+#[derive(Debug)]
+struct Activity {
+    kind: ActivityKind,
+    path: String,
+    started: Instant,
+    phase: Option<String>,
+}
+
+/// A build or download that nix reported as stopped
+#[derive(Debug, Clone)]
+pub struct Finished {
+    pub kind: ActivityKind,
+    /// Derivation path for builds, output path for downloads
+    pub path: String,
+    pub duration: Duration,
+}
+
+/// Everything learned from the log stream of a single `nix build`
+#[derive(Debug, Default)]
+pub struct BuildState {
+    running: HashMap<u64, Activity>,
+    logs: HashMap<String, VecDeque<String>>,
+    pub finished: Vec<Finished>,
+    pub errors: Vec<String>,
+}
+
+impl BuildState {
+    /// Update the state from an event, returning a message that should be shown right away
+    pub fn handle(&mut self, event: LogEvent) -> Option<String> {
+        match event {
+            LogEvent::Start { id, kind, fields } => {
+                let kind = ActivityKind::from(kind);
+                // Every download of a store path is a substitution, whose copy and file
+                // transfers are activities of their own under it, so counting those would count
+                // it twice. Other transfers are fetches of the sources of flake inputs.
+                if let ActivityKind::Build | ActivityKind::Substitute = kind {
+                    let path = fields
+                        .first()
+                        .and_then(Value::as_str)
+                        .unwrap_or_default()
+                        .to_owned();
+                    self.running.insert(
+                        id,
+                        Activity {
+                            kind,
+                            path,
+                            started: Instant::now(),
+                            phase: None,
+                        },
+                    );
+                }
+                None
+            }
+            LogEvent::Stop { id } => {
+                if let Some(activity) = self.running.remove(&id) {
+                    self.finished.push(Finished {
+                        kind: activity.kind,
+                        path: activity.path,
+                        duration: activity.started.elapsed(),
+                    });
+                }
+                None
+            }
+            LogEvent::Result { id, kind, fields } => {
+                let activity = self.running.get_mut(&id)?;
+                let field = fields.first().and_then(Value::as_str)?.to_owned();
+                match kind {
+                    RESULT_SET_PHASE => activity.phase = Some(field),
+                    RESULT_BUILD_LOG_LINE => {
+                        let tail = self.logs.entry(activity.path.clone()).or_default();
+                        if tail.len() == LOG_TAIL {
+                            tail.pop_front();
+                        }
+                        tail.push_back(field);
+                    }
+                    _ => {}
+                }
+                None
+            }
+            LogEvent::Msg { level, msg } => match level {
+                LEVEL_ERROR => {
+                    self.errors.push(msg);
+                    None
+                }
+                LEVEL_WARN => Some(msg),
+                _ => None,
+            },
+            LogEvent::Unknown => None,
+        }
+    }
+
+    fn running(&self, kind: ActivityKind) -> impl Iterator<Item = &Activity> {
+        self.running.values().filter(move |a| a.kind == kind)
+    }
+
+    pub fn finished_count(&self, kind: ActivityKind) -> usize {
+        self.finished.iter().filter(|f| f.kind == kind).count()
+    }
+
+    /// Derivations mentioned as failed by nix's error messages
+    pub fn failed_derivations(&self) -> Vec<String> {
+        let re = Regex::new(r"'(/nix/store/[^']+\.drv)'").unwrap();
+        let mut res = Vec::new();
+        for error in &self.errors {
+            for caps in re.captures_iter(error) {
+                let drv = caps[1].to_owned();
+                if !res.contains(&drv) {
+                    res.push(drv);
+                }
+            }
+        }
+        res
+    }
+
+    /// Last log lines captured for a derivation
+    pub fn log_tail(&self, drv: &str) -> impl Iterator<Item = &String> {
+        self.logs.get(drv).into_iter().flatten()
+    }
+}
+
+#[test]
+fn test_build_state() {
+    let mut state = BuildState::default();
+    let events = [
+        r#"@nix {"action":"start","id":1,"type":105,"fields":["/nix/store/abc-hello.drv","",1,1]}"#,
+        r#"@nix {"action":"result","id":1,"type":104,"fields":["buildPhase"]}"#,
+        r#"@nix {"action":"result","id":1,"type":101,"fields":["make: *** [all] Error 1"]}"#,
+        r#"@nix {"action":"start","id":2,"type":108,"fields":["/nix/store/def-world","https://cache.nixos.org"]}"#,
+        r#"@nix {"action":"start","id":3,"parent":2,"type":100,"fields":["/nix/store/def-world","https://cache.nixos.org","local"]}"#,
+        r#"@nix {"action":"start","id":4,"parent":3,"type":101,"fields":["https://cache.nixos.org/nar/def.nar.xz"]}"#,
+        r#"@nix {"action":"stop","id":4}"#,
+        r#"@nix {"action":"stop","id":3}"#,
+        r#"@nix {"action":"stop","id":2}"#,
+        r#"@nix {"action":"stop","id":1}"#,
+        r#"@nix {"action":"msg","level":0,"msg":"error: builder for '/nix/store/abc-hello.drv' failed with exit code 2"}"#,
+    ];
+
+    for line in events {
+        if let LogLine::Event(event) = LogLine::parse(line) {
+            assert_eq!(state.handle(event), None);
+        }
+    }
+
+    assert_eq!(state.finished_count(ActivityKind::Build), 1);
+    assert_eq!(state.finished_count(ActivityKind::Substitute), 1);
+    assert_eq!(state.failed_derivations(), vec!["/nix/store/abc-hello.drv"]);
+    assert_eq!(
+        state
+            .log_tail("/nix/store/abc-hello.drv")
+            .collect::<Vec<_>>(),
+        vec!["make: *** [all] Error 1"]
+    );
+}
+
+/// Renders a compact progress view of a `nix build --log-format internal-json` stream
+#[derive(Debug)]
+pub struct Progress {
+    pub state: BuildState,
+    started: Instant,
+    tty: bool,
+    last_draw: Option<Instant>,
+    status_drawn: bool,
+}
+
+impl Progress {
+    pub fn new() -> Self {
+        Self {
+            state: BuildState::default(),
+            started: Instant::now(),
+            tty: std::io::stderr().is_terminal(),
+            last_draw: None,
+            status_drawn: false,
+        }
+    }
+
+    pub fn feed(&mut self, line: &str) {
+        match LogLine::parse(line) {
+            LogLine::Raw(raw) => self.print(&raw),
+            LogLine::Event(event) => {
+                if !self.tty {
+                    if let LogEvent::Start { kind, fields, .. } = &event {
+                        let drv = fields.first().and_then(Value::as_str);
+                        if let (ActivityKind::Build, Some(drv)) = (ActivityKind::from(*kind), drv) {
+                            self.print(&format!("building {}", store_path_name(drv)));
+                        }
+                    }
+                }
+
+                if let Some(message) = self.state.handle(event) {
+                    self.print(&message);
+                }
+            }
+        }
+
+        self.draw_status(false);
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Clear the status line and print the final summary
+    pub fn finish(&mut self, success: bool) {
+        self.clear_status();
+
+        let failed = self.state.failed_derivations();
+        let built = self
+            .state
+            .finished
+            .iter()
+            .filter(|f| f.kind == ActivityKind::Build && !failed.contains(&f.path))
+            .count();
+        let fetched = self.state.finished_count(ActivityKind::Substitute);
+        let elapsed = format_duration(self.elapsed());
+
+        if success {
+            eprint!(
+                "{} Built {} and fetched {} in {}",
+                "✔".green(),
+                plural(built, "derivation"),
+                plural(fetched, "path"),
+                elapsed
+            );
+            let slowest = self
+                .state
+                .finished
+                .iter()
+                .filter(|f| f.kind == ActivityKind::Build)
+                .max_by_key(|f| f.duration);
+            if let Some(slowest) = slowest {
+                eprint!(
+                    ", slowest was {} ({})",
+                    store_path_name(&slowest.path),
+                    format_duration(slowest.duration)
+                );
+            }
+            eprintln!();
+            return;
+        }
+
+        eprintln!(
+            "{} Build failed after {} ({} built, {} fetched)",
+            "✘".red(),
+            elapsed,
+            built,
+            fetched
+        );
+
+        for drv in failed {
+            let mut tail = self.state.log_tail(&drv).peekable();
+            if tail.peek().is_none() {
+                continue;
+            }
+
+            eprintln!();
+            eprintln!(
+                "{}",
+                format!("Last log lines of {}:", store_path_name(&drv))
+                    .red()
+                    .bold()
+            );
+            for line in tail {
+                eprintln!("  {}", line.dimmed());
+            }
+        }
+
+        for error in &self.state.errors {
+            eprintln!();
+            eprintln!("{}", error);
+        }
+    }
+
+    fn print(&mut self, message: &str) {
+        self.clear_status();
+        eprintln!("{}", message);
+        self.draw_status(true);
+    }
+
+    fn clear_status(&mut self) {
+        if self.status_drawn {
+            eprint!("\r\x1b[2K");
+            self.status_drawn = false;
+        }
+    }
+
+    fn draw_status(&mut self, force: bool) {
+        if !self.tty {
+            return;
+        }
+
+        if !force
+            && self
+                .last_draw
+                .is_some_and(|last| last.elapsed() < REDRAW_INTERVAL)
+        {
+            return;
+        }
+
+        let mut building: Vec<_> = self.state.running(ActivityKind::Build).collect();
+        building.sort_by_key(|a| a.started);
+        let fetching = self.state.running(ActivityKind::Substitute).count();
+
+        let mut status = format!("[{}]", format_duration(self.started.elapsed()));
+        if !building.is_empty() {
+            let names: Vec<_> = building
+                .iter()
+                .map(|a| match &a.phase {
+                    Some(phase) => format!("{} ({phase})", store_path_name(&a.path)),
+                    None => store_path_name(&a.path).to_owned(),
+                })
+                .collect();
+            status.push_str(&format!(" building {}", names.join(", ")));
+        }
+        if fetching > 0 {
+            status.push_str(&format!(" | fetching {fetching}"));
+        }
+        status.push_str(&format!(
+            " | built {} | fetched {}",
+            self.state.finished_count(ActivityKind::Build),
+            self.state.finished_count(ActivityKind::Substitute)
+        ));
+
+        let width = textwrap::termwidth().saturating_sub(1);
+        let status: String = status.chars().take(width).collect();
+
+        eprint!("\r\x1b[2K{}", status.dimmed());
+        let _ = std::io::stderr().flush();
+
+        self.status_drawn = true;
+        self.last_draw = Some(Instant::now());
+    }
+}
+
+/// Strip the store directory and hash from a store path, leaving only its name
+pub fn store_path_name(path: &str) -> &str {
+    let base = path.rsplit('/').next().unwrap_or(path);
+    let base = base.strip_suffix(".drv").unwrap_or(base);
+    match base.split_once('-') {
+        Some((hash, name)) if hash.len() == 32 => name,
+        _ => base,
+    }
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 3600 {
+        format!("{}h{:02}m", secs / 3600, (secs % 3600) / 60)
+    } else if secs >= 60 {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+fn plural(count: usize, noun: &str) -> String {
+    if count == 1 {
+        format!("{count} {noun}")
+    } else {
+        format!("{count} {noun}s")
+    }
+}
@@ -1,38 +1,176 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::path::Path;
 use std::process::Stdio;
 use std::time::Instant;
 
-use color_eyre::eyre::{bail, Context};
+use color_eyre::eyre::{bail, eyre, Context};
 use elasticsearch_dsl::*;
 use interface::SearchArgs;
 use regex::Regex;
-use serde::Deserialize;
-use tracing::{debug, trace};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, trace, warn};
 
+use crate::search_index::Searchable;
 use crate::*;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
 #[allow(non_snake_case, dead_code)]
-struct SearchResult {
+pub struct SearchResult {
     // r#type: String,
-    package_attr_name: String,
-    package_attr_set: String,
-    package_pname: String,
-    package_pversion: String,
-    package_platforms: Vec<String>,
-    package_outputs: Vec<String>,
-    package_default_output: Option<String>,
-    package_programs: Vec<String>,
+    pub package_attr_name: String,
+    pub package_attr_set: String,
+    pub package_pname: String,
+    pub package_pversion: String,
+    pub package_platforms: Vec<String>,
+    pub package_outputs: Vec<String>,
+    pub package_default_output: Option<String>,
+    pub package_programs: Vec<String>,
     // package_license: Vec<License>,
-    package_license_set: Vec<String>,
+    pub package_license_set: Vec<String>,
     // package_maintainers: Vec<HashMap<String, String>>,
-    package_description: Option<String>,
-    package_longDescription: Option<String>,
-    package_hydra: (),
-    package_system: String,
-    package_homepage: Vec<String>,
-    package_position: Option<String>,
+    pub package_description: Option<String>,
+    pub package_longDescription: Option<String>,
+    pub package_hydra: (),
+    pub package_system: String,
+    pub package_homepage: Vec<String>,
+    pub package_position: Option<String>,
 }
 
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct OptionResult {
+    pub option_name: String,
+    pub option_description: Option<String>,
+    pub option_type: Option<String>,
+    pub option_default: Option<String>,
+    pub option_example: Option<String>,
+    /// File declaring the option, relative to nixpkgs for NixOS options
+    pub option_source: Option<String>,
+}
+
+/// A package as printed by `nh search --json`
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PackageSummary {
+    pub attr_name: String,
+    pub pname: String,
+    pub version: String,
+    pub description: Option<String>,
+    pub platforms: Vec<String>,
+    pub programs: Vec<String>,
+    pub licenses: Vec<String>,
+    pub homepage: Vec<String>,
+    pub position: Option<String>,
+    /// Version in the nixpkgs pinned by the flake, with `--pinned`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pinned_version: Option<String>,
+    /// Whether the package is in the nixpkgs pinned by the flake, with `--pinned`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub in_pinned: Option<bool>,
+    /// Whether the pinned version is older than the channel's, if the package is pinned
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub behind: Option<bool>,
+}
+
+impl PackageSummary {
+    fn new(package: &SearchResult, pinned: Option<&HashMap<String, String>>) -> Self {
+        let pinned_version = pinned
+            .and_then(|p| p.get(&package.package_attr_name))
+            .cloned();
+        Self {
+            attr_name: package.package_attr_name.clone(),
+            pname: package.package_pname.clone(),
+            version: package.package_pversion.clone(),
+            description: package.package_description.clone(),
+            platforms: package.package_platforms.clone(),
+            programs: package.package_programs.clone(),
+            licenses: package.package_license_set.clone(),
+            homepage: package.package_homepage.clone(),
+            position: package.package_position.clone(),
+            in_pinned: pinned.map(|_| pinned_version.is_some()),
+            behind: pinned_version
+                .as_deref()
+                .map(|v| behind(v, &package.package_pversion)),
+            pinned_version,
+        }
+    }
+}
+
+#[test]
+fn test_package_summary_pinned() {
+    let package = |attr_name: &str| SearchResult {
+        package_attr_name: attr_name.to_owned(),
+        package_pversion: String::from("2.0"),
+        ..Default::default()
+    };
+    let pinned = HashMap::from([(String::from("old"), String::from("1.0"))]);
+
+    let summary =
+        serde_json::to_value(PackageSummary::new(&package("old"), Some(&pinned))).unwrap();
+    assert_eq!(summary["inPinned"], true);
+    assert_eq!(summary["behind"], true);
+
+    // Not knowing the pinned version, it can't be said to be behind
+    let summary =
+        serde_json::to_value(PackageSummary::new(&package("new"), Some(&pinned))).unwrap();
+    assert_eq!(summary["inPinned"], false);
+    assert!(summary.get("behind").is_none());
+
+    let summary = serde_json::to_value(PackageSummary::new(&package("old"), None)).unwrap();
+    assert!(summary.get("inPinned").is_none() && summary.get("behind").is_none());
+}
+
+/// An option as printed by `nh search --options --json`
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OptionSummary {
+    pub name: String,
+    pub description: Option<String>,
+    #[serde(rename = "type")]
+    pub option_type: Option<String>,
+    pub default: Option<String>,
+    pub example: Option<String>,
+    pub declaration: Option<String>,
+}
+
+impl From<&OptionResult> for OptionSummary {
+    fn from(option: &OptionResult) -> Self {
+        Self {
+            name: option.option_name.clone(),
+            description: option.option_description.clone(),
+            option_type: option.option_type.clone(),
+            default: option.option_default.clone(),
+            example: option.option_example.clone(),
+            declaration: option.option_source.clone(),
+        }
+    }
+}
+
+const PACKAGE_FIELDS: &[&str] = &[
+    "package_attr_name^9",
+    "package_attr_name.*^5.3999999999999995",
+    "package_programs^9",
+    "package_programs.*^5.3999999999999995",
+    "package_pname^6",
+    "package_pname.*^3.5999999999999996",
+    "package_description^1.3",
+    "package_description.*^0.78",
+    "package_longDescription^1",
+    "package_longDescription.*^0.6",
+    "flake_name^0.5",
+    "flake_name.*^0.3",
+];
+
+const OPTION_FIELDS: &[&str] = &[
+    "option_name^6",
+    "option_name.*^3.5999999999999996",
+    "option_description^1",
+    "option_description.*^0.6",
+    "flake_name^0.5",
+    "flake_name.*^0.3",
+];
+
 macro_rules! print_hyperlink {
     ($text:expr, $link:expr) => {
         print!("\x1b]8;;{}\x07", $link);
@@ -45,55 +183,174 @@ impl SearchArgs {
     pub fn run(&self) -> Result<()> {
         trace!("args: {self:?}");
 
-        if !supported_branch(&self.channel) {
-            bail!("Channel {} is not supported!", self.channel);
+        if self.options {
+            return self.run_options();
         }
 
-        let nixpkgs_path = std::thread::spawn(|| {
-            std::process::Command::new("nix")
-                .stderr(Stdio::inherit())
-                .args(["eval", "-f", "<nixpkgs>", "path"])
-                .output()
-        });
+        let pinned = if self.pinned {
+            Some(search_index::pinned_nixpkgs(self.flake.as_deref())?)
+        } else {
+            None
+        };
+        let channel = match &pinned {
+            Some(pinned) => {
+                let channel = closest_channel(pinned.branch.as_deref());
+                if self.channel_given && self.channel != channel {
+                    warn!(
+                        "--pinned searches {channel}, the channel of the pinned nixpkgs, instead of {}",
+                        self.channel
+                    );
+                }
+                let rev = pinned.rev.as_deref().unwrap_or("unknown");
+                self.banner(&format!(
+                    "The flake pins nixpkgs {} ({}), comparing with {channel}",
+                    &rev[..rev.len().min(7)],
+                    pinned.branch.as_deref().unwrap_or("no branch"),
+                ));
+                channel
+            }
+            None => self.channel.clone(),
+        };
+
+        let (documents, mut nixpkgs_path) = match &self.nixpkgs_from {
+            Some(from) => {
+                let nixpkgs = search_index::nixpkgs_path(from, self.flake.as_deref())?;
+                let documents =
+                    self.search_offline(&nixpkgs, || search_index::packages(&nixpkgs))?;
+                (documents, nixpkgs.to_string_lossy().into_owned())
+            }
+            None => {
+                let query = self.query("package", PACKAGE_FIELDS, "package_attr_name");
+                self.search_online(&channel, query)?
+            }
+        };
+
+        let pinned_versions = match &pinned {
+            Some(pinned) => {
+                let attr_names: Vec<&str> = documents
+                    .iter()
+                    .map(|d| d.package_attr_name.as_str())
+                    .collect();
+                nixpkgs_path = pinned.path.to_string_lossy().into_owned();
+                Some(search_index::versions(&pinned.path, &attr_names)?)
+            }
+            None => None,
+        };
+
+        if self.json {
+            let summary: Vec<PackageSummary> = documents
+                .iter()
+                .map(|d| PackageSummary::new(d, pinned_versions.as_ref()))
+                .collect();
+            println!("{}", serde_json::to_string_pretty(&summary)?);
+            return Ok(());
+        }
+
+        self.banner("Most relevant results at the end\n");
+        print_results(&documents, &nixpkgs_path, pinned_versions.as_ref());
+
+        Ok(())
+    }
+
+    /// Print a progress message, on stderr when stdout is reserved for the JSON output
+    fn banner(&self, message: &str) {
+        if self.json {
+            eprintln!("{message}");
+        } else {
+            println!("{message}");
+        }
+    }
+
+    fn run_options(&self) -> Result<()> {
+        let (options, nixpkgs_path) = match (&self.options_json, &self.nixpkgs_from) {
+            (Some(path), _) => (
+                self.search_offline(path, || search_index::options(path))?,
+                String::new(),
+            ),
+            (None, Some(from)) => {
+                let nixpkgs = search_index::nixpkgs_path(from, self.flake.as_deref())?;
+                let path = search_index::options_json(from, self.flake.as_deref())?;
+                let options = self.search_offline(&path, || search_index::options(&path))?;
+                (options, nixpkgs.to_string_lossy().into_owned())
+            }
+            (None, None) => {
+                let query = self.query("option", OPTION_FIELDS, "option_name");
+                self.search_online(&self.channel, query)?
+            }
+        };
+
+        if self.json {
+            let summary: Vec<OptionSummary> = options.iter().map(Into::into).collect();
+            println!("{}", serde_json::to_string_pretty(&summary)?);
+            return Ok(());
+        }
+
+        self.banner("Most relevant results at the end\n");
+        print_options(&options, &nixpkgs_path);
+
+        Ok(())
+    }
+
+    /// Rank the packages or options of a local index like search.nixos.org does
+    fn search_offline<T, F>(&self, source: &Path, index: F) -> Result<Vec<T>>
+    where
+        T: Searchable + Clone,
+        F: FnOnce() -> Result<Vec<T>>,
+    {
+        self.banner(&format!("Searching {} offline...", source.display()));
+        let then = Instant::now();
+        let documents = search_index::rank(&index()?, &self.query, self.limit);
+        let elapsed = then.elapsed();
+        debug!(?elapsed);
+        self.banner(&format!("Took {}ms", elapsed.as_millis()));
+
+        Ok(documents)
+    }
 
-        let query = Search::new().from(0).size(self.limit).query(
-            Query::bool().filter(Query::term("type", "package")).must(
+    /// Query of search.nixos.org for packages or options, matching the fields with their
+    /// boosts, or `name_field` with a wildcard
+    fn query(&self, kind: &str, fields: &[&str], name_field: &str) -> Search {
+        Search::new().from(0).size(self.limit).query(
+            Query::bool().filter(Query::term("type", kind)).must(
                 Query::dis_max()
                     .tie_breaker(0.7)
                     .query(
-                        Query::multi_match(
-                            [
-                                "package_attr_name^9",
-                                "package_attr_name.*^5.3999999999999995",
-                                "package_programs^9",
-                                "package_programs.*^5.3999999999999995",
-                                "package_pname^6",
-                                "package_pname.*^3.5999999999999996",
-                                "package_description^1.3",
-                                "package_description.*^0.78",
-                                "package_longDescription^1",
-                                "package_longDescription.*^0.6",
-                                "flake_name^0.5",
-                                "flake_name.*^0.3",
-                            ],
-                            self.query.as_str(),
-                        )
-                        .r#type(TextQueryType::CrossFields)
-                        .analyzer("whitespace")
-                        .auto_generate_synonyms_phrase_query(false)
-                        .operator(Operator::And),
+                        Query::multi_match(fields.iter().copied(), self.query.as_str())
+                            .r#type(TextQueryType::CrossFields)
+                            .analyzer("whitespace")
+                            .auto_generate_synonyms_phrase_query(false)
+                            .operator(Operator::And),
                     )
                     .query(
-                        Query::wildcard("package_attr_name", format!("*{}*", self.query))
+                        Query::wildcard(name_field, format!("*{}*", self.query))
                             .case_insensitive(true),
                     ),
             ),
-        );
+        )
+    }
+
+    fn search_online<T: DeserializeOwned>(
+        &self,
+        channel: &str,
+        query: Search,
+    ) -> Result<(Vec<T>, String)> {
+        if !supported_branch(channel) {
+            bail!("Channel {channel} is not supported!");
+        }
 
-        println!(
-            "Querying search.nixos.org, with channel {}...",
-            self.channel
-        );
+        // Only used to print the positions of the results, which the JSON leaves as they are
+        let nixpkgs_path = (!self.json).then(|| {
+            std::thread::spawn(|| {
+                std::process::Command::new("nix")
+                    .stderr(Stdio::inherit())
+                    .args(["eval", "-f", "<nixpkgs>", "path"])
+                    .output()
+            })
+        });
+
+        self.banner(&format!(
+            "Querying search.nixos.org, with channel {channel}..."
+        ));
         let then = Instant::now();
 
         let client = reqwest::blocking::Client::new();
@@ -101,8 +358,7 @@ impl SearchArgs {
             // I guess 42 is the version of the backend API
             // TODO: have a GH action or something check if they updated this thing
             .post(format!(
-                "https://search.nixos.org/backend/latest-42-{}/_search",
-                self.channel
+                "https://search.nixos.org/backend/latest-42-{channel}/_search"
             ))
             .json(&query)
             .header("User-Agent", format!("nh/{}", crate::NH_VERSION))
@@ -120,9 +376,7 @@ impl SearchArgs {
         let elapsed = then.elapsed();
         debug!(?elapsed);
         trace!(?response);
-        println!("Took {}ms", elapsed.as_millis());
-        println!("Most relevant results at the end");
-        println!();
+        self.banner(&format!("Took {}ms", elapsed.as_millis()));
 
         let parsed_response: SearchResponse = response
             .json()
@@ -130,67 +384,146 @@ impl SearchArgs {
         trace!(?parsed_response);
 
         let documents = parsed_response
-            .documents::<SearchResult>()
+            .documents::<T>()
             .context("parsing search document")?;
 
-        let hyperlinks = supports_hyperlinks::supports_hyperlinks();
-        debug!(?hyperlinks);
+        let nixpkgs_path = match nixpkgs_path {
+            Some(thread) => {
+                let output = thread
+                    .join()
+                    .map_err(|_| eyre!("Evaluating the nixpkgs path location panicked"))?
+                    .context("Evaluating the nixpkgs path location")?;
+                String::from_utf8(output.stdout).context("Reading the nixpkgs path location")?
+            }
+            None => String::new(),
+        };
 
-        let nixpkgs_path = String::from_utf8(
-            nixpkgs_path
-                .join()
-                .unwrap()
-                .context("Evaluating the nixpkgs path location")?
-                .stdout,
-        )
-        .unwrap();
+        Ok((documents, nixpkgs_path))
+    }
+}
 
-        for elem in documents.iter().rev() {
-            println!();
-            use owo_colors::OwoColorize;
-            trace!("{elem:#?}");
+/// Print the results with the most relevant one at the end, closest to the prompt
+fn print_results(
+    documents: &[SearchResult],
+    nixpkgs_path: &str,
+    pinned: Option<&HashMap<String, String>>,
+) {
+    let hyperlinks = supports_hyperlinks::supports_hyperlinks();
+    debug!(?hyperlinks);
 
-            print!("{}", elem.package_attr_name.blue());
-            let v = &elem.package_pversion;
-            if !v.is_empty() {
-                print!(" ({})", v.green());
+    for elem in documents.iter().rev() {
+        println!();
+        use owo_colors::OwoColorize;
+        trace!("{elem:#?}");
+
+        print!("{}", elem.package_attr_name.blue());
+        let v = &elem.package_pversion;
+        if !v.is_empty() {
+            print!(" ({})", v.green());
+        }
+        if let Some(pinned) = pinned {
+            match pinned.get(&elem.package_attr_name) {
+                Some(p) if behind(p, v) => print!(" pinned {} {}", p.yellow(), "(behind)".red()),
+                Some(p) => print!(" pinned {}", p),
+                None => print!(" {}", "not in the pinned nixpkgs".yellow()),
             }
+        }
 
-            println!();
+        println!();
 
-            if let Some(ref desc) = elem.package_description {
-                let desc = desc.replace('\n', " ");
-                for line in textwrap::wrap(&desc, textwrap::Options::with_termwidth()) {
-                    println!("  {}", line);
-                }
+        if let Some(ref desc) = elem.package_description {
+            let desc = desc.replace('\n', " ");
+            for line in textwrap::wrap(&desc, textwrap::Options::with_termwidth()) {
+                println!("  {}", line);
             }
+        }
 
-            for url in elem.package_homepage.iter() {
-                print!("  Homepage: ");
-                if hyperlinks {
-                    print_hyperlink!(url, url);
-                } else {
-                    println!("{}", url);
-                }
+        for url in elem.package_homepage.iter() {
+            print!("  Homepage: ");
+            if hyperlinks {
+                print_hyperlink!(url, url);
+            } else {
+                println!("{}", url);
             }
+        }
 
-            if let Some(position) = &elem.package_position {
-                let position = position.split(':').next().unwrap();
-                print!("  Defined at: ");
-                if hyperlinks {
-                    let postion_trimmed = position
-                        .split(':')
-                        .next()
-                        .expect("Removing line number from position");
-
-                    print_hyperlink!(position, format!("file://{nixpkgs_path}/{postion_trimmed}"));
-                } else {
-                    println!("{}", position);
-                }
+        if let Some(position) = &elem.package_position {
+            let position = position.split(':').next().unwrap();
+            print!("  Defined at: ");
+            if hyperlinks {
+                let postion_trimmed = position
+                    .split(':')
+                    .next()
+                    .expect("Removing line number from position");
+
+                print_hyperlink!(position, format!("file://{nixpkgs_path}/{postion_trimmed}"));
+            } else {
+                println!("{}", position);
             }
         }
+    }
+}
 
-        Ok(())
+/// Print options like packages, with their type, default, example and declaration
+fn print_options(options: &[OptionResult], nixpkgs_path: &str) {
+    use owo_colors::OwoColorize;
+
+    let hyperlinks = supports_hyperlinks::supports_hyperlinks();
+    // search.nixos.org renders the descriptions to HTML
+    let tags = Regex::new(r"<[^>]+>").unwrap();
+
+    let print_value = |label: &str, value: &str| {
+        let value = value.trim();
+        if !value.contains('\n') {
+            println!("  {label}: {value}");
+            return;
+        }
+        println!("  {label}:");
+        for line in value.lines() {
+            println!("    {line}");
+        }
+    };
+
+    for option in options.iter().rev() {
+        println!();
+        trace!("{option:#?}");
+
+        print!("{}", option.option_name.blue());
+        if let Some(option_type) = &option.option_type {
+            print!(" ({})", option_type.green());
+        }
+        println!();
+
+        if let Some(desc) = &option.option_description {
+            let desc = tags.replace_all(desc, "").replace('\n', " ");
+            for line in textwrap::wrap(desc.trim(), textwrap::Options::with_termwidth()) {
+                println!("  {}", line);
+            }
+        }
+
+        if let Some(default) = &option.option_default {
+            print_value("Default", default);
+        }
+        if let Some(example) = &option.option_example {
+            print_value("Example", example);
+        }
+
+        if let Some(source) = &option.option_source {
+            print!("  Declared in: ");
+            let path = if source.starts_with('/') {
+                Some(source.clone())
+            } else if !nixpkgs_path.is_empty() && !source.starts_with('<') {
+                Some(format!("{}/{source}", nixpkgs_path.trim()))
+            } else {
+                None
+            };
+            match path {
+                Some(path) if hyperlinks => {
+                    print_hyperlink!(source, format!("file://{path}"));
+                }
+                _ => println!("{}", source),
+            }
+        }
     }
 }
 
@@ -205,6 +538,44 @@ fn supported_branch<S: AsRef<str>>(branch: S) -> bool {
     re.is_match(branch)
 }
 
+/// Whether a pinned version is older than the one of the channel
+fn behind(pinned: &str, channel: &str) -> bool {
+    !channel.is_empty() && util::compare_versions(pinned, channel) == Ordering::Less
+}
+
+/// The channel search.nixos.org has that is closest to a branch of nixpkgs, so that
+/// `nixos-24.05-small` or `release-24.05` are searched in `nixos-24.05`
+fn closest_channel(branch: Option<&str>) -> String {
+    let Some(branch) = branch else {
+        return "nixos-unstable".to_owned();
+    };
+
+    let release = Regex::new(r"[0-9]{2}\.[0-9]{2}").unwrap();
+    match release.find(branch) {
+        Some(release) => format!("nixos-{}", release.as_str()),
+        None => "nixos-unstable".to_owned(),
+    }
+}
+
+#[test]
+fn test_closest_channel() {
+    for (branch, channel) in [
+        (Some("nixos-24.05"), "nixos-24.05"),
+        (Some("nixos-24.05-small"), "nixos-24.05"),
+        (Some("nixpkgs-23.11-darwin"), "nixos-23.11"),
+        (Some("release-24.05"), "nixos-24.05"),
+        (Some("nixos-unstable"), "nixos-unstable"),
+        (Some("nixpkgs-unstable"), "nixos-unstable"),
+        (Some("master"), "nixos-unstable"),
+        (None, "nixos-unstable"),
+    ] {
+        assert_eq!(closest_channel(branch), channel, "{branch:?}");
+    }
+    assert!(behind("13.0.0", "14.1.0"));
+    assert!(!behind("14.1.0", "14.1.0"));
+    assert!(!behind("14.1.0", ""));
+}
+
 #[test]
 fn test_supported_branch() {
     assert!(supported_branch("nixos-unstable"));
@@ -0,0 +1,660 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use color_eyre::eyre::{bail, Context};
+use serde::Deserialize;
+use tracing::{debug, info, warn};
+
+use crate::flake_lock;
+use crate::flake_ref;
+use crate::installable::{self, join_attribute, Installable, OutputsSpec};
+use crate::interface::SearchNixpkgsFrom;
+use crate::search::{OptionResult, SearchResult};
+use crate::util;
+use crate::Result;
+
+/// Run nix and return its stdout, failing with its stderr
+fn nix(args: &[&str]) -> Result<String> {
+    let mut cmd = Command::new("nix");
+    cmd.args(args);
+    debug!(?cmd);
+
+    let output = cmd.output().wrap_err("Running nix")?;
+    if !output.status.success() {
+        bail!(
+            "nix {} failed: {}",
+            args.first().unwrap_or(&""),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+/// Location of the nixpkgs to search, either the `nixpkgs` input of a flake or `<nixpkgs>`
+pub fn nixpkgs_path(from: &SearchNixpkgsFrom, flake: Option<&str>) -> Result<PathBuf> {
+    let path = match from {
+        SearchNixpkgsFrom::Flake => {
+            let Some(flake) = flake else {
+                bail!("Searching the nixpkgs of a flake needs --flake or $NH_FLAKE");
+            };
+            let reference = flake_ref::resolve(flake.split('#').next().unwrap_or(flake))?;
+            let expr = format!(
+                "(builtins.getFlake {}).inputs.nixpkgs.outPath",
+                nix_string(&reference)?
+            );
+            nix(&["eval", "--raw", "--impure", "--expr", &expr])
+                .wrap_err(format!("Getting the nixpkgs input of {reference}"))?
+        }
+        SearchNixpkgsFrom::Path => {
+            nix(&["eval", "--raw", "--impure", "--expr", "toString <nixpkgs>"])
+                .wrap_err("Finding <nixpkgs>")?
+        }
+    };
+
+    // <nixpkgs> is usually a channel symlink, but the cache is keyed on what it points to
+    fs::canonicalize(&path).wrap_err(format!("Finding nixpkgs at {path}"))
+}
+
+/// The nixpkgs a flake locks, to compare search results against
+#[derive(Debug)]
+pub struct Pinned {
+    /// Branch the input tracks, like `nixos-24.05`, if it tracks one
+    pub branch: Option<String>,
+    pub rev: Option<String>,
+    pub path: PathBuf,
+}
+
+/// The nixpkgs input locked by a flake
+pub fn pinned_nixpkgs(flake: Option<&str>) -> Result<Pinned> {
+    let Some(flake) = flake else {
+        bail!("Searching the pinned nixpkgs needs --flake or $NH_FLAKE");
+    };
+    let reference = flake_ref::resolve(flake.split('#').next().unwrap_or(flake))?;
+    let lock = flake_lock::for_reference(&reference)?;
+    let Some((locked, branch)) = lock.input("nixpkgs") else {
+        bail!("Flake {reference} doesn't have a nixpkgs input");
+    };
+
+    Ok(Pinned {
+        branch: branch.map(str::to_owned),
+        rev: locked.rev.clone(),
+        path: nixpkgs_path(&SearchNixpkgsFrom::Flake, Some(&reference))?,
+    })
+}
+
+/// Versions of some packages in a nixpkgs, leaving out the ones it doesn't have
+pub fn versions(nixpkgs: &Path, attr_names: &[&str]) -> Result<HashMap<String, String>> {
+    let mut packages = String::new();
+    for attr_name in attr_names {
+        let path: Vec<String> = attr_name
+            .split('.')
+            .map(serde_json::to_string)
+            .collect::<std::result::Result<_, _>>()?;
+        packages.push_str(&format!(
+            "{} = version [ {} ];\n",
+            serde_json::to_string(attr_name)?,
+            path.join(" ")
+        ));
+    }
+
+    let expr = format!(
+        r#"let
+  pkgs = import {} {{ config = {{ allowAliases = false; }}; }};
+  version = path:
+    let res = builtins.tryEval ((pkgs.lib.attrByPath path null pkgs).version or null);
+    in if res.success then res.value else null;
+in {{
+{packages}}}"#,
+        serde_json::to_string(&nixpkgs.to_string_lossy())?
+    );
+
+    let output = nix(&["eval", "--json", "--impure", "--expr", &expr])
+        .wrap_err("Evaluating the pinned versions")?;
+    let versions: HashMap<String, Option<String>> = serde_json::from_str(&output)?;
+    Ok(versions
+        .into_iter()
+        .filter_map(|(attr_name, version)| Some((attr_name, version?)))
+        .collect())
+}
+
+/// A package as printed by `nix-env -qa --json --meta`
+#[derive(Debug, Deserialize)]
+struct NixEnvPackage {
+    pname: Option<String>,
+    version: Option<String>,
+    system: Option<String>,
+    #[serde(default)]
+    outputs: BTreeMap<String, Option<String>>,
+    #[serde(rename = "outputName")]
+    output_name: Option<String>,
+    #[serde(default)]
+    meta: serde_json::Value,
+}
+
+/// Strings of a meta attribute that is either a string or a list of them
+fn strings(value: Option<&serde_json::Value>) -> Vec<String> {
+    match value {
+        Some(serde_json::Value::String(s)) => vec![s.clone()],
+        Some(serde_json::Value::Array(values)) => values
+            .iter()
+            .filter_map(|v| v.as_str().map(str::to_owned))
+            .collect(),
+        _ => vec![],
+    }
+}
+
+/// License names, which are attribute sets, lists of them or plain strings
+fn licenses(value: Option<&serde_json::Value>) -> Vec<String> {
+    let name = |license: &serde_json::Value| {
+        ["spdxId", "shortName", "fullName"]
+            .into_iter()
+            .find_map(|key| license.get(key)?.as_str())
+            .or_else(|| license.as_str())
+            .map(str::to_owned)
+    };
+
+    match value {
+        Some(serde_json::Value::Array(values)) => values.iter().filter_map(name).collect(),
+        Some(license) => name(license).into_iter().collect(),
+        None => vec![],
+    }
+}
+
+impl NixEnvPackage {
+    fn into_result(self, attr_name: String, nixpkgs: &str) -> SearchResult {
+        let meta = &self.meta;
+        let text = |key| meta.get(key).and_then(|v| v.as_str()).map(str::to_owned);
+
+        let package_attr_set = match attr_name.rsplit_once('.') {
+            Some((set, _)) => set.to_owned(),
+            None => String::from("No package set"),
+        };
+        // Make positions relative like the ones of search.nixos.org
+        let package_position = text("position").map(|position| {
+            position
+                .strip_prefix(nixpkgs)
+                .map(|p| p.trim_start_matches('/').to_owned())
+                .unwrap_or(position)
+        });
+
+        SearchResult {
+            package_pname: self.pname.unwrap_or_default(),
+            package_pversion: self.version.unwrap_or_default(),
+            package_platforms: strings(meta.get("platforms")),
+            package_outputs: self.outputs.into_keys().collect(),
+            package_default_output: self.output_name,
+            package_programs: text("mainProgram").into_iter().collect(),
+            package_license_set: licenses(meta.get("license")),
+            package_description: text("description"),
+            package_longDescription: text("longDescription"),
+            package_hydra: (),
+            package_system: self.system.unwrap_or_default(),
+            package_homepage: strings(meta.get("homepage")),
+            package_position,
+            package_attr_set,
+            package_attr_name: attr_name,
+        }
+    }
+}
+
+/// Where the package index of a nixpkgs is cached, if it can be: only store paths never
+/// change, a checkout at another path can have been edited since it was indexed
+fn index_file(nixpkgs: &Path) -> Result<Option<PathBuf>> {
+    if !nixpkgs.starts_with("/nix/store") {
+        return Ok(None);
+    }
+    let hash = util::sha256([nixpkgs.as_os_str().as_encoded_bytes()]);
+    Ok(Some(
+        util::cache_dir()?.join(format!("packages-{hash}.json")),
+    ))
+}
+
+#[test]
+fn test_index_file() {
+    let store = Path::new("/nix/store/0123456789abcdfghijklmnpqrsvwxyz-source");
+    let file = index_file(store).unwrap().unwrap();
+    assert_eq!(file, index_file(store).unwrap().unwrap());
+    assert_eq!(file.file_name().unwrap().len(), "packages-.json".len() + 64);
+
+    assert!(index_file(Path::new("/home/user/nixpkgs"))
+        .unwrap()
+        .is_none());
+}
+
+/// Remove the index files written by older versions, which were keyed on a hash that
+/// changed with every build of nh and so are never read again
+fn remove_old_indexes(dir: &Path) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        let Some(hash) = name
+            .strip_prefix("packages-")
+            .and_then(|n| n.strip_suffix(".json"))
+        else {
+            continue;
+        };
+        if hash.len() == 16 && hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            debug!(path = ?entry.path(), "Removing an old package index");
+            let _ = fs::remove_file(entry.path());
+        }
+    }
+}
+
+/// All the packages of a nixpkgs, evaluated once and then read from the cache if it is a
+/// store path
+pub fn packages(nixpkgs: &Path) -> Result<Vec<SearchResult>> {
+    let path = index_file(nixpkgs)?;
+    if let Some(path) = &path {
+        match fs::read_to_string(path) {
+            Ok(s) => match serde_json::from_str(&s) {
+                Ok(index) => {
+                    debug!(?path, "Using the cached package index");
+                    return Ok(index);
+                }
+                Err(err) => debug!(?err, "Rebuilding malformed package index"),
+            },
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err).wrap_err(format!("Reading {}", path.display())),
+        }
+        info!(
+            "Indexing the packages of {}, this takes a while the first time",
+            nixpkgs.display()
+        );
+    } else {
+        info!(
+            "Indexing the packages of {}, which isn't cached since it's outside the Nix store",
+            nixpkgs.display()
+        );
+    }
+    let mut cmd = Command::new("nix-env");
+    cmd.arg("-f")
+        .arg(nixpkgs)
+        .args(["-qaP", "--json", "--meta"])
+        .args(["--arg", "config", "{ allowAliases = false; }"]);
+    debug!(?cmd);
+
+    let output = cmd.output().wrap_err("Running nix-env")?;
+    if !output.status.success() {
+        bail!(
+            "Evaluating the packages of {} failed: {}",
+            nixpkgs.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    let packages: BTreeMap<String, NixEnvPackage> =
+        serde_json::from_slice(&output.stdout).wrap_err("Parsing the nix-env output")?;
+    let nixpkgs = nixpkgs.to_string_lossy();
+    let index: Vec<SearchResult> = packages
+        .into_iter()
+        .map(|(attr_name, package)| package.into_result(attr_name, &nixpkgs))
+        .collect();
+
+    let Some(path) = path else {
+        return Ok(index);
+    };
+    let res = (|| -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+            remove_old_indexes(parent);
+        }
+        fs::write(&path, serde_json::to_string(&index)?)?;
+        Ok(())
+    })();
+    if let Err(err) = res {
+        warn!(?err, "Failed to cache the package index");
+    }
+
+    Ok(index)
+}
+
+/// Nix string literal of `s`, which unlike JSON also escapes interpolations
+fn nix_string(s: &str) -> Result<String> {
+    Ok(serde_json::to_string(s)?.replace("${", "\\${"))
+}
+
+#[test]
+fn test_nix_string() {
+    assert_eq!(nix_string("a.b").unwrap(), r#""a.b""#);
+    assert_eq!(nix_string(r#"say "${x}""#).unwrap(), r#""say \"\${x}\"""#);
+}
+
+/// Options JSON of a configuration, from a flake or `<nixpkgs/nixos>`
+///
+/// The configuration of a flake is the one its `#ATTRPATH` selects, the NixOS configuration
+/// of this machine otherwise. Home Manager configurations have no options JSON of their own,
+/// so theirs is built with the `nixosOptionsDoc` of their nixpkgs.
+pub fn options_json(from: &SearchNixpkgsFrom, flake: Option<&str>) -> Result<PathBuf> {
+    let attribute = "config.system.build.manual.optionsJSON";
+    let out = match from {
+        SearchNixpkgsFrom::Flake => {
+            let Some(flake) = flake else {
+                bail!("Searching the options of a flake needs --flake or $NH_FLAKE");
+            };
+            let Installable::Flake {
+                reference,
+                attribute: configuration,
+                ..
+            } = installable::parse_flake(flake)?
+            else {
+                unreachable!("parse_flake always returns a flake installable");
+            };
+            let reference = flake_ref::resolve(&reference)?;
+
+            let configuration = if configuration.is_empty() {
+                let installable = Installable::Flake {
+                    reference: reference.clone(),
+                    attribute: vec![],
+                    outputs: OutputsSpec::Default,
+                };
+                let hostname = hostname::get().context("Failed to get hostname")?;
+                let hostname = flake_ref::select_configuration(
+                    &installable,
+                    "nixosConfigurations",
+                    &hostname.to_string_lossy(),
+                    std::iter::empty::<&str>(),
+                )?;
+                vec![String::from("nixosConfigurations"), hostname]
+            } else {
+                configuration
+            };
+            let name = join_attribute(&configuration)?;
+            info!("Building the options of {name}");
+
+            if configuration
+                .first()
+                .is_some_and(|c| c == "homeConfigurations")
+            {
+                let mut select = format!("(builtins.getFlake {})", nix_string(&reference)?);
+                for elem in &configuration {
+                    select.push('.');
+                    select.push_str(&nix_string(elem)?);
+                }
+                let expr = format!(
+                    "let configuration = {select}; in (configuration.pkgs.nixosOptionsDoc {{ \
+                     options = builtins.removeAttrs configuration.options [ \"_module\" ]; \
+                     }}).optionsJSON"
+                );
+                nix(&[
+                    "build",
+                    "--impure",
+                    "--no-link",
+                    "--print-out-paths",
+                    "--expr",
+                    &expr,
+                ])?
+            } else {
+                nix(&[
+                    "build",
+                    "--no-link",
+                    "--print-out-paths",
+                    &format!("{reference}#{name}.{attribute}"),
+                ])?
+            }
+        }
+        SearchNixpkgsFrom::Path => {
+            info!("Building the options of <nixpkgs/nixos>");
+            nix(&[
+                "build",
+                "--impure",
+                "--no-link",
+                "--print-out-paths",
+                "--file",
+                "<nixpkgs/nixos>",
+                attribute,
+            ])?
+        }
+    };
+
+    Ok(PathBuf::from(out).join("share/doc/nixos/options.json"))
+}
+
+/// An option as written by `nixosOptionsDoc`, for NixOS and Home Manager alike
+#[derive(Debug, Deserialize)]
+struct DocOption {
+    #[serde(default)]
+    declarations: Vec<serde_json::Value>,
+    default: Option<serde_json::Value>,
+    description: Option<serde_json::Value>,
+    example: Option<serde_json::Value>,
+    #[serde(rename = "type")]
+    option_type: Option<String>,
+}
+
+/// Text of a value that is either a literal expression, Markdown or plain JSON
+fn literal(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::Object(o) if o.contains_key("_type") => o
+            .get("text")
+            .and_then(|text| text.as_str())
+            .unwrap_or_default()
+            .to_owned(),
+        serde_json::Value::String(s) => s.clone(),
+        value => value.to_string(),
+    }
+}
+
+/// Text of a default or example, which are JSON values unless they are literal expressions
+fn value_text(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(_) => value.to_string(),
+        value => literal(value),
+    }
+}
+
+/// All the options of an options JSON file
+pub fn options(path: &Path) -> Result<Vec<OptionResult>> {
+    let s = fs::read_to_string(path).wrap_err(format!("Reading {}", path.display()))?;
+    parse_options(&s).wrap_err(format!("Parsing {}", path.display()))
+}
+
+fn parse_options(s: &str) -> Result<Vec<OptionResult>> {
+    let options: BTreeMap<String, DocOption> = serde_json::from_str(s)?;
+
+    Ok(options
+        .into_iter()
+        .map(|(name, option)| OptionResult {
+            option_name: name,
+            option_description: option.description.as_ref().map(literal),
+            option_type: option.option_type,
+            option_default: option.default.as_ref().map(value_text),
+            option_example: option.example.as_ref().map(value_text),
+            option_source: option.declarations.first().map(|declaration| {
+                declaration
+                    .get("name")
+                    .unwrap_or(declaration)
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_owned()
+            }),
+        })
+        .collect())
+}
+
+#[test]
+fn test_parse_options() {
+    let options = parse_options(
+        r#"{
+            "services.nginx.enable": {
+                "declarations": ["nixos/modules/services/web-servers/nginx/default.nix"],
+                "default": false,
+                "description": "Whether to enable Nginx Web Server.",
+                "example": true,
+                "loc": ["services", "nginx", "enable"],
+                "readOnly": false,
+                "type": "boolean"
+            },
+            "programs.git.userName": {
+                "declarations": [{"name": "<home-manager/modules/programs/git.nix>", "url": "https://x"}],
+                "default": {"_type": "literalExpression", "text": "null"},
+                "description": {"_type": "mdDoc", "text": "Default user name to use."},
+                "type": "null or string"
+            }
+        }"#,
+    )
+    .unwrap();
+
+    assert_eq!(options[0].option_name, "programs.git.userName");
+    assert_eq!(options[0].option_default.as_deref(), Some("null"));
+    assert_eq!(options[0].option_example, None);
+    assert_eq!(
+        options[0].option_source.as_deref(),
+        Some("<home-manager/modules/programs/git.nix>")
+    );
+    assert_eq!(
+        options[0].option_description.as_deref(),
+        Some("Default user name to use.")
+    );
+    assert_eq!(options[1].option_default.as_deref(), Some("false"));
+    assert_eq!(options[1].option_type.as_deref(), Some("boolean"));
+}
+
+/// How well a field matches a term, with the same boosts as the query of search.nixos.org:
+/// whole words count fully, parts of words a bit more than half
+fn field_score(field: &str, term: &str, boost: f64) -> f64 {
+    let field = field.to_lowercase();
+    if field
+        .split(|c: char| !c.is_alphanumeric() && c != '-' && c != '_')
+        .any(|word| word == term)
+    {
+        boost
+    } else if field.contains(term) {
+        boost * 0.6
+    } else {
+        0.0
+    }
+}
+
+/// Something that can be ranked against a query
+pub trait Searchable {
+    /// Name matched by the wildcard query
+    fn name(&self) -> &str;
+
+    /// Text fields with their boosts, using the weights of search.nixos.org
+    fn fields(&self) -> Vec<(&str, f64)>;
+}
+
+impl Searchable for SearchResult {
+    fn name(&self) -> &str {
+        &self.package_attr_name
+    }
+
+    fn fields(&self) -> Vec<(&str, f64)> {
+        let mut res = vec![
+            (self.package_attr_name.as_str(), 9.0),
+            (self.package_pname.as_str(), 6.0),
+            (self.package_description.as_deref().unwrap_or_default(), 1.3),
+            (
+                self.package_longDescription.as_deref().unwrap_or_default(),
+                1.0,
+            ),
+        ];
+        res.extend(self.package_programs.iter().map(|p| (p.as_str(), 9.0)));
+        res
+    }
+}
+
+impl Searchable for OptionResult {
+    fn name(&self) -> &str {
+        &self.option_name
+    }
+
+    fn fields(&self) -> Vec<(&str, f64)> {
+        vec![
+            (self.option_name.as_str(), 6.0),
+            (self.option_description.as_deref().unwrap_or_default(), 1.0),
+        ]
+    }
+}
+
+/// Relevance of a package or option for a query, if it matches
+pub fn score<T: Searchable>(item: &T, query: &str) -> Option<f64> {
+    let query = query.to_lowercase();
+    let fields = item.fields();
+
+    // Like the multi_match query, every term has to match some field
+    let mut terms = 0.0;
+    for term in query.split_whitespace() {
+        let best = fields
+            .iter()
+            .map(|(field, boost)| field_score(field, term, *boost))
+            .fold(0.0, f64::max);
+
+        if best == 0.0 {
+            terms = 0.0;
+            break;
+        }
+        terms += best;
+    }
+
+    let wildcard = if item.name().to_lowercase().contains(&query) {
+        1.0
+    } else {
+        0.0
+    };
+
+    // dis_max of both queries, with the same tie breaker
+    let (best, other) = if terms > wildcard {
+        (terms, wildcard)
+    } else {
+        (wildcard, terms)
+    };
+    (best > 0.0).then_some(best + 0.7 * other)
+}
+
+/// The `limit` most relevant items for a query, most relevant first
+pub fn rank<T: Searchable + Clone>(index: &[T], query: &str, limit: u64) -> Vec<T> {
+    let mut matches: Vec<(f64, &T)> = index
+        .iter()
+        .filter_map(|item| Some((score(item, query)?, item)))
+        .collect();
+    matches.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+
+    matches
+        .into_iter()
+        .take(limit as usize)
+        .map(|(_, item)| item.clone())
+        .collect()
+}
+
+#[test]
+fn test_rank() {
+    let package = |attr_name: &str, programs: &[&str], description: &str| SearchResult {
+        package_attr_name: attr_name.to_owned(),
+        package_pname: attr_name.rsplit('.').next().unwrap().to_owned(),
+        package_programs: programs.iter().map(|p| p.to_string()).collect(),
+        package_description: Some(description.to_owned()),
+        ..Default::default()
+    };
+    let index = [
+        package(
+            "silver-searcher",
+            &["ag"],
+            "Code-searching tool similar to ack",
+        ),
+        package("ripgrep-all", &["rga"], "Ripgrep, but also search in PDFs"),
+        package("ripgrep", &["rg"], "Fast line-oriented regex search tool"),
+        package(
+            "hello",
+            &["hello"],
+            "A program that produces a familiar greeting",
+        ),
+    ];
+
+    let names = |query, limit| {
+        rank(&index, query, limit)
+            .into_iter()
+            .map(|p| p.package_attr_name)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(names("ripgrep", 10), ["ripgrep", "ripgrep-all"]);
+    assert_eq!(names("rg", 10), ["ripgrep", "ripgrep-all"]);
+    assert_eq!(names("search tool", 10), ["silver-searcher", "ripgrep"]);
+    assert_eq!(names("ripgrep", 1), ["ripgrep"]);
+    assert!(names("nothing", 10).is_empty());
+}
@@ -1,71 +1,557 @@
-use tracing::warn;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::{bail, eyre, Context};
+use tracing::{debug, info, warn};
 
 use crate::commands::Command;
+use crate::flake_lock::{self, Change, ChangeSummary, FlakeLock};
+use crate::flake_ref;
+use crate::git;
 use crate::installable::Installable;
+use crate::interface::{CommitLock, PullArgs, UpdateArgs};
+use crate::json;
+use crate::nix_info;
+use crate::progress::store_path_name;
+use crate::util;
 use crate::Result;
 
-pub fn update(installable: &Installable, input: Option<String>) -> Result<()> {
+/// The lock file of a local flake, as it was before updating it
+#[derive(Debug)]
+pub struct LockUpdate {
+    dir: PathBuf,
+    before: Option<String>,
+    commit: Option<CommitLock>,
+    committed: bool,
+}
+
+impl LockUpdate {
+    fn path(&self) -> PathBuf {
+        self.dir.join("flake.lock")
+    }
+
+    /// Inputs that changed since the update started
+    pub fn changes(&self) -> Result<Vec<Change>> {
+        let new = FlakeLock::read(self.path())?;
+        let old = match &self.before {
+            Some(before) => FlakeLock::parse(before)?,
+            None => FlakeLock::default(),
+        };
+        Ok(old.diff(&new))
+    }
+
+    /// Commit the updated lock file, listing the changed inputs in the message
+    pub fn commit(&self) -> Result<()> {
+        let changes = self.changes()?;
+        if changes.is_empty() {
+            info!("flake.lock is unchanged, not committing it");
+            return Ok(());
+        }
+
+        info!("Committing flake.lock");
+        let message = flake_lock::commit_message(&changes);
+        git::run(&self.dir, &["add", "flake.lock"])?;
+        git::run(
+            &self.dir,
+            &["commit", "--message", &message, "--", "flake.lock"],
+        )
+        .wrap_err("Committing flake.lock")?;
+
+        Ok(())
+    }
+
+    /// Commit the lock file if `--commit-lock` asked for it once this point of the rebuild is reached
+    pub fn reached(&mut self, point: CommitLock) -> Result<()> {
+        if self.commit != Some(point) || self.committed {
+            return Ok(());
+        }
+
+        // Keep the updated lock file even if committing it fails, the build went fine
+        self.committed = true;
+        self.commit()
+    }
+
+    /// The rebuild is over, revert the lock file if it failed before it could be committed
+    pub fn finished(&self, success: bool) {
+        if self.commit.is_none() || self.committed {
+            return;
+        }
+
+        if success {
+            warn!("flake.lock was not committed, as the configuration wasn't activated");
+        } else {
+            self.revert();
+        }
+    }
+
+    /// Put the lock file back as it was, warning if that fails
+    pub fn revert(&self) {
+        info!("Reverting flake.lock");
+        let res = match &self.before {
+            Some(before) => fs::write(self.path(), before),
+            None => fs::remove_file(self.path()),
+        };
+
+        if let Err(err) = res {
+            warn!("Failed to revert {}: {err}", self.path().display());
+        }
+    }
+}
+
+/// Update the inputs of a flake, returning the previous lock file if the flake is local, or the
+/// channels used by other installables, root's ones when elevating. In dry mode only the names
+/// to update are checked.
+pub fn update(
+    installable: &Installable,
+    args: &UpdateArgs,
+    elevate: bool,
+    dry: bool,
+) -> Result<Option<LockUpdate>> {
     match installable {
         Installable::Flake { reference, .. } => {
-            let mut cmd = Command::new("nix").args(["flake", "update"]);
-
-            if let Some(i) = input {
-                cmd = cmd.arg(&i).message(format!("Updating flake input {}", i));
+            let nix = nix_info::supported()?;
+            let inputs = if args.update_input.is_empty() {
+                vec![]
             } else {
+                resolve_names(&args.update_input, &flake_inputs(reference)?, "input")?
+            };
+            if dry {
+                // Nothing to commit or to revert afterwards
+                if args.commit_lock.is_some() {
+                    warn!("--commit-lock has no effect as dry run was requested");
+                }
+                info!("Not updating the flake inputs in dry mode");
+                return Ok(None);
+            }
+
+            let lock = match flake_ref::local_dir(reference) {
+                Some(dir) => {
+                    let before = match fs::read_to_string(dir.join("flake.lock")) {
+                        Ok(content) => Some(content),
+                        Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+                        Err(err) => return Err(err).wrap_err("Reading flake.lock"),
+                    };
+                    Some(LockUpdate {
+                        dir,
+                        before,
+                        commit: args.commit_lock,
+                        committed: false,
+                    })
+                }
+                None => {
+                    if args.commit_lock.is_some() {
+                        warn!("--commit-lock only works with local flakes, {reference} is not one");
+                    }
+                    None
+                }
+            };
+
+            let mut cmd = Command::new("nix").args(nix.flake_update_args(reference, &inputs));
+            if inputs.is_empty() {
                 cmd = cmd.message("Updating all flake inputs");
+            } else {
+                cmd = cmd.message(format!("Updating flake inputs {}", inputs.join(", ")));
             }
 
-            cmd.arg("--flake").arg(reference).run()?;
+            cmd.run()?;
+
+            if let Some(lock) = &lock {
+                let changes = lock.changes()?;
+                let json_to_stdout = args.update_json.as_deref() == Some(Path::new("-"));
+                if changes.is_empty() {
+                    info!("No flake inputs changed");
+                } else if json_to_stdout {
+                    // stdout carries the JSON summary instead
+                    eprint!("{}", flake_lock::summary(&changes));
+                } else {
+                    print!("{}", flake_lock::summary(&changes));
+                }
+                if let Some(path) = &args.update_json {
+                    write_summary(path, &changes)?;
+                }
+            } else if args.update_json.is_some() {
+                warn!("--update-json only works with local flakes, {reference} is not one");
+            }
+
+            Ok(lock)
+        }
+        Installable::File { .. } | Installable::Expression { .. } => {
+            if args.commit_lock.is_some() || args.update_json.is_some() {
+                warn!("--commit-lock and --update-json only work with flakes");
+            }
+            update_channels(&args.update_input, elevate, dry)?;
+            Ok(None)
         }
-        _ => {
+        Installable::Store { .. } | Installable::Derivation { .. } => {
             warn!(
-                "Only flake installables can be updated, {} is not supported",
+                "Only flake, file and expression installables can be updated, {} is not supported",
                 installable.str_kind()
             );
+            Ok(None)
         }
     }
+}
+
+/// Run `nix-channel --update`, the equivalent of `nixos-rebuild --upgrade`, and report what changed
+fn update_channels(channels: &[String], elevate: bool, dry: bool) -> Result<()> {
+    // Check the names first, nix-channel only fails on them after updating the others
+    let channels = if channels.is_empty() {
+        vec![]
+    } else {
+        resolve_names(channels, &channel_names(elevate)?, "channel")?
+    };
+    if dry {
+        info!("Not updating the channels in dry mode");
+        return Ok(());
+    }
+
+    let profile = channels_profile(elevate);
+    let before = profile.as_deref().map(ChannelState::read);
+
+    let mut cmd = Command::new("nix-channel")
+        .arg("--update")
+        .args(&channels)
+        .elevate(elevate);
+    if channels.is_empty() {
+        cmd = cmd.message("Updating channels");
+    } else {
+        cmd = cmd.message(format!("Updating channels {}", channels.join(", ")));
+    }
+    cmd.run()?;
+
+    match (before, profile.as_deref().map(ChannelState::read)) {
+        (Some(before), Some(after)) => before.print_changes(&after),
+        _ => debug!("Couldn't find the channels profile, not reporting the changes"),
+    }
 
     Ok(())
 }
 
-pub fn pull(installable: &Installable, update: bool, dry: bool) -> Result<()> {
-    match installable {
-        Installable::Flake { reference, .. } => {
-            Command::new("git")
-                .args(["-C", reference, "pull"])
-                .dry(dry)
-                .message("Pulling git repository")
-                .run()?;
-
-            if update {
-                let status = Command::new("git")
-                    .args(["-C", reference, "diff", "--name-only", "--diff-filter=U"])
-                    .message("Checking for conflicts")
-                    .run_capture()?;
-
-                if let Some(conflict) = status {
-                    if conflict == *"flake.lock\n" {
-                        Command::new("git")
-                            .args(["-C", reference, "reset", "flake.lock"])
-                            .message("Resetting flake.lock")
-                            .run()?;
-                        Command::new("git")
-                            .args(["-C", reference, "checkout", "flake.lock"])
-                            .message("Checking out flake.lock")
-                            .run()?;
-                    } else {
-                        panic!("Conflicts dectected that were more than just flake.lock");
-                    }
-                }
+/// Names of the channels subscribed to, of root or of the current user
+fn channel_names(root: bool) -> Result<Vec<String>> {
+    let output = Command::new("nix-channel")
+        .arg("--list")
+        .elevate(root)
+        .run_capture()
+        .wrap_err("Listing the channels")?
+        .unwrap_or_default();
+
+    Ok(output
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .map(String::from)
+        .collect())
+}
+
+/// Profile holding the channels, of root or of the current user
+fn channels_profile(root: bool) -> Option<PathBuf> {
+    if root {
+        return Some(PathBuf::from(
+            "/nix/var/nix/profiles/per-user/root/channels",
+        ));
+    }
+
+    let mut candidates = Vec::new();
+    if let Ok(home) = std::env::var("HOME") {
+        candidates.push(PathBuf::from(home).join(".local/state/nix/profiles/channels"));
+    }
+    if let Ok(user) = std::env::var("USER") {
+        candidates.push(
+            PathBuf::from("/nix/var/nix/profiles/per-user")
+                .join(user)
+                .join("channels"),
+        );
+    }
+
+    candidates.into_iter().find(|p| p.is_symlink())
+}
+
+/// Generation of the channels profile and the version of each channel in it
+#[derive(Debug, Default, PartialEq, Eq)]
+struct ChannelState {
+    generation: Option<u32>,
+    channels: BTreeMap<String, String>,
+}
+
+impl ChannelState {
+    fn read(profile: &Path) -> Self {
+        let generation = fs::read_link(profile)
+            .ok()
+            .and_then(|link| parse_generation(&link.file_name()?.to_string_lossy()));
+
+        let channels = fs::read_dir(profile)
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| {
+                let entry = entry.ok()?;
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let target = fs::canonicalize(entry.path()).ok()?;
+                // Channels point inside their store path, like /nix/store/<hash>-nixos-24.05.1234.abcdef/nixos
+                let store_path = target.components().nth(3)?;
+                let version = store_path_name(&store_path.as_os_str().to_string_lossy()).to_owned();
+                (name != "manifest.nix").then_some((name, version))
+            })
+            .collect();
+
+        Self {
+            generation,
+            channels,
+        }
+    }
+
+    fn print_changes(&self, after: &ChannelState) {
+        let generation = |g: Option<u32>| g.map(|g| g.to_string()).unwrap_or_else(|| "?".into());
+
+        if self == after {
+            info!(
+                "Channels are already up to date, at generation {}",
+                generation(after.generation)
+            );
+            return;
+        }
+
+        info!(
+            "Channels went from generation {} to {}",
+            generation(self.generation),
+            generation(after.generation)
+        );
+        for (name, version) in &after.channels {
+            match self.channels.get(name) {
+                Some(old) if old != version => info!("- {name}: {old} → {version}"),
+                Some(_) => {}
+                None => info!("- {name}: added at {version}"),
             }
         }
-        _ => {
-            warn!(
-                "Only flake installables can be pulled, {} is not supported",
-                installable.str_kind()
+        for name in self.channels.keys() {
+            if !after.channels.contains_key(name) {
+                info!("- {name}: removed");
+            }
+        }
+    }
+}
+
+/// Generation number of a profile link, like `channels-42-link`
+fn parse_generation(link: &str) -> Option<u32> {
+    link.strip_suffix("-link")?.rsplit('-').next()?.parse().ok()
+}
+
+#[test]
+fn test_parse_generation() {
+    assert_eq!(parse_generation("channels-42-link"), Some(42));
+    assert_eq!(parse_generation("channels"), None);
+}
+
+impl UpdateArgs {
+    /// Whether any kind of update was requested
+    pub fn enabled(&self) -> bool {
+        self.update || !self.update_input.is_empty()
+    }
+}
+
+/// Names of the direct inputs of a flake, as listed by `nix flake metadata`
+fn flake_inputs(reference: &str) -> Result<Vec<String>> {
+    let output = Command::new("nix")
+        .args(["flake", "metadata", "--json", reference])
+        .message("Reading flake inputs")
+        .run_capture()?
+        .unwrap_or_default();
+    let metadata: serde_json::Value =
+        serde_json::from_str(&output).wrap_err("Parsing nix flake metadata")?;
+
+    let metadata = json::Value::new(&metadata);
+    let locks = metadata.get("locks")?;
+    let root = locks
+        .get("root")?
+        .inner
+        .as_str()
+        .ok_or_else(|| eyre!("Malformed nix flake metadata, locks.root is not a string"))?;
+    let inputs = locks.get("nodes")?.get(root)?.get("inputs")?;
+
+    Ok(inputs
+        .inner
+        .as_object()
+        .map(|inputs| inputs.keys().cloned().collect())
+        .unwrap_or_default())
+}
+
+/// Expand the names and globs given to `--update-input` into the flake inputs or channels
+/// to update, suggesting the closest name for a typo
+fn resolve_names(patterns: &[String], available: &[String], kind: &str) -> Result<Vec<String>> {
+    let mut res: Vec<String> = Vec::new();
+
+    for pattern in patterns {
+        let matches: Vec<&String> = available
+            .iter()
+            .filter(|input| util::glob_match(pattern, input))
+            .collect();
+
+        if matches.is_empty() {
+            let suggestion = util::did_you_mean(pattern, available.iter().map(String::as_str))
+                .map(|s| format!(", did you mean `{s}`?"))
+                .unwrap_or_default();
+            bail!(
+                "No {kind} matches `{pattern}`{suggestion}\nAvailable {kind}s: {}",
+                available.join(", ")
             );
         }
+
+        for input in matches {
+            if !res.contains(input) {
+                res.push(input.clone());
+            }
+        }
+    }
+
+    Ok(res)
+}
+
+#[test]
+fn test_resolve_names() {
+    let available: Vec<String> = ["home-manager", "nixpkgs", "nixpkgs-stable"]
+        .map(String::from)
+        .to_vec();
+    let resolve = |patterns: &[&str]| {
+        let patterns: Vec<String> = patterns.iter().map(|p| p.to_string()).collect();
+        resolve_names(&patterns, &available, "input")
+    };
+
+    assert_eq!(
+        resolve(&["nixpkgs*", "home-manager", "nixpkgs"]).unwrap(),
+        ["nixpkgs", "nixpkgs-stable", "home-manager"]
+    );
+
+    let err = resolve(&["home-manger"]).unwrap_err().to_string();
+    assert!(err.contains("did you mean `home-manager`?"), "{err}");
+    assert!(resolve(&["sops*"]).is_err());
+}
+
+fn write_summary(path: &Path, changes: &[Change]) -> Result<()> {
+    let summary: Vec<ChangeSummary> = changes.iter().map(Into::into).collect();
+    let json = serde_json::to_string_pretty(&summary)?;
+
+    if path == Path::new("-") {
+        println!("{json}");
+    } else {
+        fs::write(path, json + "\n").wrap_err(format!("Writing {}", path.display()))?;
     }
 
     Ok(())
 }
+
+/// Pull the local checkout of a flake with the strategy asked for, never leaving it mid-merge
+/// unless the user chooses to resolve the conflicts themselves
+pub fn pull(installable: &Installable, args: &PullArgs, update: bool, dry: bool) -> Result<()> {
+    let Installable::Flake { reference, .. } = installable else {
+        warn!(
+            "Only flake installables can be pulled, {} is not supported",
+            installable.str_kind()
+        );
+        return Ok(());
+    };
+
+    let Some(dir) = flake_ref::local_dir(reference) else {
+        warn!("Only local flakes can be pulled, {reference} is not one");
+        return Ok(());
+    };
+
+    // Without --rebase or --ff-only, pull.rebase and pull.ff of the git config decide
+    let mut pull_args = vec!["pull"];
+    if args.rebase {
+        pull_args.push("--rebase");
+    } else if args.ff_only {
+        pull_args.push("--ff-only");
+    }
+    if args.autostash {
+        pull_args.push("--autostash");
+    }
+
+    info!("Pulling git repository");
+    if dry {
+        debug!("Not running git {} in dry mode", pull_args.join(" "));
+        return Ok(());
+    }
+
+    let dirty = git::run(&dir, &["status", "--porcelain", "--untracked-files=no"])?;
+    if !dirty.trim().is_empty() && !args.autostash {
+        bail!(
+            "{} has uncommitted changes, commit or stash them first, or pass --autostash\n{}",
+            dir.display(),
+            dirty.trim_end()
+        );
+    }
+
+    if git::status(&dir, &pull_args)? {
+        return Ok(());
+    }
+
+    let conflicts: Vec<String> = git::run(&dir, &["diff", "--name-only", "--diff-filter=U"])?
+        .lines()
+        .map(str::to_owned)
+        .collect();
+    if conflicts.is_empty() {
+        bail!("git {} failed", pull_args.join(" "));
+    }
+
+    let rebasing = in_rebase(&dir)?;
+
+    // The lock file is about to be regenerated anyway, so any side of the conflict will do
+    if update && conflicts == ["flake.lock"] {
+        info!("Resolving the flake.lock conflict, it will be updated afterwards");
+        git::run(&dir, &["checkout", "HEAD", "--", "flake.lock"])?;
+        git::run(&dir, &["add", "flake.lock"])?;
+        if rebasing {
+            git::run(&dir, &["-c", "core.editor=true", "rebase", "--continue"])?;
+        } else {
+            git::run(&dir, &["commit", "--no-edit"])?;
+        }
+        return Ok(());
+    }
+
+    let abort = if std::io::stdin().is_terminal() {
+        warn!("git pull left conflicts in: {}", conflicts.join(", "));
+        let choice = dialoguer::Select::new()
+            .with_prompt("What do you want to do?")
+            .items(&[
+                "Abort the pull and go back to how the repository was",
+                "Keep the conflicts to resolve them manually",
+            ])
+            .default(0)
+            .interact()?;
+        choice == 0
+    } else {
+        true
+    };
+
+    if abort {
+        let abort_args: &[&str] = if rebasing {
+            &["rebase", "--abort"]
+        } else {
+            &["merge", "--abort"]
+        };
+        git::run(&dir, abort_args).wrap_err("Aborting the pull")?;
+        bail!(
+            "Pulling {} conflicted in {}, the pull was aborted",
+            dir.display(),
+            conflicts.join(", ")
+        );
+    }
+
+    bail!(
+        "Pulling {} conflicted in {}, resolve them and run nh again",
+        dir.display(),
+        conflicts.join(", ")
+    );
+}
+
+/// Whether the repository is in the middle of a rebase
+fn in_rebase(dir: &Path) -> Result<bool> {
+    for state in ["rebase-merge", "rebase-apply"] {
+        let path = git::run(dir, &["rev-parse", "--git-path", state])?;
+        if dir.join(path.trim()).exists() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
@@ -1,62 +1,236 @@
-extern crate semver;
-
 use std::path::{Path, PathBuf};
-use std::process::Command;
-use std::str;
 
-use color_eyre::{eyre, Result};
-use semver::Version;
+use color_eyre::eyre::Context;
+use color_eyre::Result;
+use sha2::{Digest, Sha256};
 use tempfile::TempDir;
 
-/// Compares two semantic versions and returns their order.
-///
-/// This function takes two version strings, parses them into `semver::Version` objects, and compares them.
-/// It returns an `Ordering` indicating whether the current version is less than, equal to, or
-/// greater than the target version.
-///
-/// # Arguments
-///
-/// * `current` - A string slice representing the current version.
-/// * `target` - A string slice representing the target version to compare against.
-///
-/// # Returns
-///
-/// * `Result<std::cmp::Ordering>` - The comparison result.
-pub fn compare_semver(current: &str, target: &str) -> Result<std::cmp::Ordering> {
-    let current = Version::parse(current)?;
-    let target = Version::parse(target)?;
-
-    Ok(current.cmp(&target))
-}
-
-/// Retrieves the installed Nix version as a string.
-///
-/// This function executes the `nix --version` command, parses the output to extract the version string,
-/// and returns it. If the version string cannot be found or parsed, it returns an error.
-///
-/// # Returns
-///
-/// * `Result<String>` - The Nix version string or an error if the version cannot be retrieved.
-pub fn get_nix_version() -> Result<String> {
-    let output = Command::new("nix").arg("--version").output()?;
-
-    let output_str = str::from_utf8(&output.stdout)?;
-    let version_str = output_str
-        .lines()
-        .next()
-        .ok_or_else(|| eyre::eyre!("No version string found"))?;
-
-    // Extract the version substring using a regular expression
-    let re = regex::Regex::new(r"\d+\.\d+\.\d+")?;
-    if let Some(captures) = re.captures(version_str) {
-        let version = captures
-            .get(0)
-            .ok_or_else(|| eyre::eyre!("No version match found"))?
-            .as_str();
-        return Ok(version.to_string());
+/// Look up an executable in `$PATH`
+pub fn find_in_path<S: AsRef<std::ffi::OsStr>>(name: S) -> Option<PathBuf> {
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .map(|dir| dir.join(name.as_ref()))
+        .find(|candidate| candidate.is_file())
+}
+
+/// Hex SHA-256 of some pieces of data, which unlike `DefaultHasher` stays the same across
+/// builds of nh, to name what is cached on disk
+pub fn sha256<I>(parts: I) -> String
+where
+    I: IntoIterator,
+    I::Item: AsRef<[u8]>,
+{
+    let mut hasher = Sha256::new();
+    for part in parts {
+        let part = part.as_ref();
+        hasher.update((part.len() as u64).to_le_bytes());
+        hasher.update(part);
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+#[test]
+fn test_sha256() {
+    assert_eq!(
+        sha256(["abc"]),
+        "ce91dc5eec0139adf091900d225971d6ad246a845bad791b5693a9d0d55dd391"
+    );
+    assert_ne!(sha256(["ab", "c"]), sha256(["a", "bc"]));
+}
+
+/// Directory for nh's persistent state, like the build history
+pub fn state_dir() -> Result<PathBuf> {
+    xdg_dir("XDG_STATE_HOME", ".local/state")
+}
+
+/// Directory for nh's user configuration
+pub fn config_dir() -> Result<PathBuf> {
+    xdg_dir("XDG_CONFIG_HOME", ".config")
+}
+
+/// Directory for nh's caches, which can be deleted at any time
+pub fn cache_dir() -> Result<PathBuf> {
+    xdg_dir("XDG_CACHE_HOME", ".cache")
+}
+
+/// Resolve `$XDG_*` or its fallback under `$HOME`, with nh's own subdirectory
+fn xdg_dir(var: &str, fallback: &str) -> Result<PathBuf> {
+    let base = match std::env::var_os(var) {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(
+            std::env::var("HOME").wrap_err(format!("Neither ${var} nor $HOME are set"))?,
+        )
+        .join(fallback),
+    };
+
+    Ok(base.join("nh"))
+}
+
+/// Compare versions like `builtins.compareVersions`: numbers numerically, words
+/// alphabetically, with `pre` before anything and words before numbers
+pub fn compare_versions(a: &str, b: &str) -> std::cmp::Ordering {
+    use std::cmp::Ordering;
+
+    fn components(version: &str) -> Vec<&str> {
+        let mut res = Vec::new();
+        let mut start = 0;
+        let chars: Vec<(usize, char)> = version.char_indices().collect();
+        for (i, &(pos, c)) in chars.iter().enumerate() {
+            if c == '.' || c == '-' {
+                start = pos + 1;
+                continue;
+            }
+            let end = chars.get(i + 1).map_or(version.len(), |&(next, _)| next);
+            let next = version[end..].chars().next();
+            // A component ends at a separator or where digits and other characters meet
+            if next.is_none_or(|n| n == '.' || n == '-' || n.is_ascii_digit() != c.is_ascii_digit())
+            {
+                res.push(&version[start..end]);
+                start = end;
+            }
+        }
+        res
+    }
+
+    fn less(a: &str, b: &str) -> bool {
+        let numeric = |s: &str| !s.is_empty() && s.bytes().all(|c| c.is_ascii_digit());
+        match (numeric(a), numeric(b)) {
+            (true, true) => {
+                // Without leading zeros, a longer number is bigger, however long it is
+                let (a, b) = (a.trim_start_matches('0'), b.trim_start_matches('0'));
+                (a.len(), a) < (b.len(), b)
+            }
+            _ if a.is_empty() && numeric(b) => true,
+            _ if a == "pre" && b != "pre" => true,
+            _ if b == "pre" => false,
+            (false, true) => true,
+            (true, false) => false,
+            (false, false) => a < b,
+        }
+    }
+
+    let (a, b) = (components(a), components(b));
+    for i in 0..a.len().max(b.len()) {
+        let (a, b) = (
+            a.get(i).copied().unwrap_or(""),
+            b.get(i).copied().unwrap_or(""),
+        );
+        if less(a, b) {
+            return Ordering::Less;
+        }
+        if less(b, a) {
+            return Ordering::Greater;
+        }
+    }
+    Ordering::Equal
+}
+
+#[test]
+fn test_compare_versions() {
+    use std::cmp::Ordering::*;
+
+    for (a, b, ordering) in [
+        ("1.0", "2.3", Less),
+        ("2.1", "2.3", Less),
+        ("2.3", "2.3", Equal),
+        ("2.5", "2.3", Greater),
+        ("3.1", "2.3", Greater),
+        ("2.3.1", "2.3", Greater),
+        ("2.3.1", "2.3a", Greater),
+        ("2.3pre1", "2.3", Less),
+        ("2.3pre3", "2.3pre12", Less),
+        ("2.3a", "2.3c", Less),
+        ("2.3pre1", "2.3c", Less),
+        ("2.3pre1", "2.3q", Less),
+        ("14.1.0", "13.0.0", Greater),
+        ("0-unstable-2024-01-10", "0-unstable-2023-12-01", Greater),
+    ] {
+        assert_eq!(compare_versions(a, b), ordering, "{a} {b}");
     }
+}
+
+/// Match a name against a shell-like glob, supporting `*` and `?`
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    let (mut p, mut n) = (0, 0);
+    // Position of the last `*` and of the name when it was reached, to backtrack to
+    let mut star = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    p = star_p + 1;
+                    n = star_n + 1;
+                    star = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[test]
+fn test_glob_match() {
+    assert!(glob_match("nixpkgs*", "nixpkgs"));
+    assert!(glob_match("nixpkgs*", "nixpkgs-stable"));
+    assert!(glob_match("*-stable", "nixpkgs-stable"));
+    assert!(glob_match("home-?anager", "home-manager"));
+    assert!(!glob_match("nixpkgs*", "home-manager"));
+    assert!(!glob_match("nixpkgs", "nixpkgs-stable"));
+}
+
+/// Number of single character edits to turn `a` into `b`
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut previous = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous + usize::from(ca != *cb);
+            previous = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(previous + 1);
+        }
+    }
+
+    row[b.len()]
+}
+
+/// The candidate closest to a mistyped name, if any is close enough
+pub fn did_you_mean<'c, I>(name: &str, candidates: I) -> Option<&'c str>
+where
+    I: IntoIterator<Item = &'c str>,
+{
+    let max = (name.chars().count() / 3).max(1);
+    candidates
+        .into_iter()
+        .map(|c| (edit_distance(name, c), c))
+        .filter(|(distance, _)| *distance <= max)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, c)| c)
+}
 
-    Err(eyre::eyre!("Failed to extract version"))
+#[test]
+fn test_did_you_mean() {
+    assert_eq!(edit_distance("kitten", "sitting"), 3);
+    let inputs = ["nixpkgs", "home-manager", "nix-darwin"];
+    assert_eq!(did_you_mean("nixpgks", inputs), Some("nixpkgs"));
+    assert_eq!(did_you_mean("home-manger", inputs), Some("home-manager"));
+    assert_eq!(did_you_mean("sops-nix", inputs), None);
 }
 
 pub trait MaybeTempPath: std::fmt::Debug {